@@ -0,0 +1,226 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use argh::FromArgs;
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::rustls::Certificate;
+
+use crate::err::Supernova;
+use crate::tls;
+
+/// Certificates are made to last so that clients pinning them on first use
+/// don't see them change.
+pub const DEFAULT_DAYS: u32 = 3650;
+
+/// Write a PKCS#8 key and a self-signed certificate for one or more
+/// hostnames.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "gen-cert")]
+pub struct GenCertArgs {
+    /// hostname to put in the certificate. may be given more than once.
+    #[argh(option, short = 'n')]
+    hostname: Vec<String>,
+
+    /// certificate file to write.
+    #[argh(option, default = "PathBuf::from(\"laika.crt\")")]
+    cert: PathBuf,
+
+    /// key file to write.
+    #[argh(option, default = "PathBuf::from(\"laika.key\")")]
+    key: PathBuf,
+
+    /// key type: ecdsa (P-256) or ed25519.
+    #[argh(option, default = "KeyType::Ecdsa")]
+    key_type: KeyType,
+
+    /// days the certificate is valid for.
+    #[argh(option, default = "DEFAULT_DAYS")]
+    days: u32,
+
+    /// overwrite existing files.
+    #[argh(switch)]
+    force: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyType {
+    Ecdsa,
+    Ed25519,
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(format!("unknown key type {}, expected ecdsa or ed25519", s)),
+        }
+    }
+}
+
+/// Runs `laika gen-cert`.
+pub fn run(args: &GenCertArgs) -> Result<(), Supernova> {
+    if args.hostname.is_empty() {
+        return Err(Supernova::boom("gen-cert needs at least one --hostname"));
+    }
+    if !args.force {
+        for path in [&args.cert, &args.key] {
+            if path.exists() {
+                let msg = format!(
+                    "{} already exists, use --force to replace it",
+                    path.display()
+                );
+                return Err(Supernova::boom(&msg));
+            }
+        }
+    }
+
+    let fingerprint = generate(
+        &args.hostname,
+        &args.cert,
+        &args.key,
+        args.key_type,
+        args.days,
+    )?;
+    println!(
+        "wrote {} and {} for {}\nSHA-256 fingerprint: {}",
+        args.cert.display(),
+        args.key.display(),
+        args.hostname.join(", "),
+        fingerprint
+    );
+
+    Ok(())
+}
+
+/// Writes a key to `key` and a certificate for `hostnames`, which are also
+/// its subject alternative names, to `cert`. Returns the certificate's
+/// SHA-256 fingerprint.
+pub fn generate(
+    hostnames: &[String],
+    cert: &Path,
+    key: &Path,
+    key_type: KeyType,
+    days: u32,
+) -> Result<String, Supernova> {
+    let mut params = rcgen::CertificateParams::new(hostnames.to_vec());
+    params.alg = match key_type {
+        KeyType::Ecdsa => &rcgen::PKCS_ECDSA_P256_SHA256,
+        KeyType::Ed25519 => &rcgen::PKCS_ED25519,
+    };
+    if let Some(name) = hostnames.first() {
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name.as_str());
+    }
+    // a day of slack for clients whose clocks are behind
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - Duration::days(1);
+    params.not_after = now + Duration::days(i64::from(days));
+
+    let generated = match rcgen::Certificate::from_params(params) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("Could not generate certificate: {}", e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+    // ECDSA signatures differ every time, so the fingerprint has to come
+    // from the certificate that's written out
+    let pem = match generated.serialize_pem() {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("Could not sign certificate: {}", e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+    let der = match rustls_pemfile::certs(&mut pem.as_bytes()) {
+        Ok(mut v) if !v.is_empty() => v.remove(0),
+        _ => return Err(Supernova::boom("Could not read back generated certificate")),
+    };
+
+    write(key, &generated.serialize_private_key_pem(), 0o600)?;
+    write(cert, &pem, 0o644)?;
+
+    Ok(tls::fingerprint(&Certificate(der)))
+}
+
+fn write(path: &Path, contents: &str, mode: u32) -> Result<(), Supernova> {
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut fd| fd.write_all(contents.as_bytes()));
+
+    if let Err(e) = written {
+        let msg = format!("Could not write {}: {}", path.display(), e);
+        return Err(Supernova::boom(&msg));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio_rustls::rustls::{sign, PrivateKey};
+    use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+    #[test]
+    fn generated_pairs() {
+        let dir = std::env::temp_dir().join(format!("laika-certgen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names = vec![String::from("example.org"), String::from("127.0.0.1")];
+
+        for key_type in [KeyType::Ecdsa, KeyType::Ed25519] {
+            let cert = dir.join(format!("{:?}.crt", key_type));
+            let key = dir.join(format!("{:?}.key", key_type));
+            let fingerprint = generate(&names, &cert, &key, key_type, 30).unwrap();
+
+            let certs = crate::conf::load_certs(&cert).unwrap();
+            assert_eq!(tls::fingerprint(&certs[0]), fingerprint);
+            let pem = fs::read(&key).unwrap();
+            let keys = rustls_pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap();
+            assert_eq!(keys.len(), 1);
+            assert!(sign::any_supported_type(&PrivateKey(keys[0].clone())).is_ok());
+
+            let (_, parsed) = X509Certificate::from_der(&certs[0].0).unwrap();
+            let sans = parsed.subject_alternative_name().unwrap().unwrap();
+            assert_eq!(sans.value.general_names.len(), 2);
+            assert!(matches!(
+                sans.value.general_names[0],
+                GeneralName::DNSName("example.org")
+            ));
+            assert!(parsed.validity().is_valid());
+        }
+
+        assert_eq!("Ed25519".parse::<KeyType>().unwrap(), KeyType::Ed25519);
+        assert!("rsa".parse::<KeyType>().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,552 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+use tokio::process::{Child, ChildStderr, ChildStdout, Command};
+use tokio::time::{self, Instant, Sleep};
+use tokio_rustls::rustls::ServerConnection;
+use url::Url;
+
+use crate::err::Supernova;
+use crate::file;
+use crate::response;
+use crate::tls;
+use crate::LAIKA_VERSION;
+
+// How much of a script's stderr ends up in the log.
+const MAX_STDERR_BYTES: usize = 4096;
+
+/// Settings for running CGI scripts.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CgiConf {
+    // request path prefixes below which every file is a script
+    paths: Vec<String>,
+    // globs matched against the file name of a file, like "*.cgi", or
+    // against its whole request path when they contain a slash
+    patterns: Vec<String>,
+    // seconds a script may run for
+    timeout: u64,
+    // bytes a script may write to stdout
+    max_output: u64,
+    #[serde(skip)]
+    compiled: Vec<Pattern>,
+}
+
+impl Default for CgiConf {
+    fn default() -> Self {
+        CgiConf {
+            paths: Vec::new(),
+            patterns: Vec::new(),
+            timeout: 10,
+            max_output: 8 * 1024 * 1024,
+            compiled: Vec::new(),
+        }
+    }
+}
+
+impl CgiConf {
+    /// Checks the patterns and compiles them for matching.
+    pub fn load(&mut self) -> Result<(), Supernova> {
+        let mut compiled = Vec::new();
+        for pattern in &self.patterns {
+            match Pattern::new(pattern) {
+                Ok(v) => compiled.push(v),
+                Err(e) => {
+                    let msg = format!("Invalid CGI pattern {}: {}", pattern, e);
+                    return Err(Supernova::boom(&msg));
+                }
+            }
+        }
+        self.compiled = compiled;
+
+        Ok(())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    fn enabled(&self) -> bool {
+        !self.paths.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// Whether the file at `req_path` should be executed.
+    fn is_script(&self, req_path: &str) -> bool {
+        let name = req_path.rsplit('/').next().unwrap_or("");
+        self.paths.iter().any(|p| file::path_under(req_path, p))
+            || self.compiled.iter().any(|p| {
+                if p.as_str().contains('/') {
+                    p.matches_with(req_path, file::MATCH_OPTIONS)
+                } else {
+                    p.matches_with(name, file::MATCH_OPTIONS)
+                }
+            })
+    }
+}
+
+/// A script found while walking a request path.
+#[derive(Debug, PartialEq)]
+pub struct Script {
+    path: PathBuf,
+    // decoded request path of the script itself
+    script_name: String,
+    // whatever followed the script in the request path
+    path_info: String,
+}
+
+impl Script {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn script_name(&self) -> &str {
+        &self.script_name
+    }
+    pub fn path_info(&self) -> &str {
+        &self.path_info
+    }
+}
+
+/// Walks `root_path` down from `root` until it reaches a file, and returns
+/// that file if it's configured as a script. `url_prefix` is the part of the
+/// request path that isn't below `root`, like `/~alice` for user directories.
+pub async fn find_script(
+    conf: &CgiConf,
+    root: &Path,
+    url_prefix: &str,
+    root_path: &str,
+) -> Result<Option<Script>, Supernova> {
+    if !conf.enabled() {
+        return Ok(None);
+    }
+
+    let segments: Vec<&str> = root_path.split('/').filter(|s| !s.is_empty()).collect();
+    let mut candidate = root.to_path_buf();
+
+    for (i, seg) in segments.iter().enumerate() {
+        candidate.push(seg);
+
+        // let the static file handler report anything missing
+        let path = match file::confine(root, &candidate).await {
+            Ok(v) => v,
+            Err(_) => return Ok(None),
+        };
+        let metadata = fs::metadata(&path).await.map_err(file::wrap_io_err)?;
+        if metadata.is_dir() {
+            continue;
+        }
+
+        let script_name = format!("{}/{}", url_prefix, segments[..=i].join("/"));
+        if !conf.is_script(&script_name) {
+            return Ok(None);
+        }
+
+        if metadata.permissions().mode() & 0o111 == 0 {
+            let msg = format!("CGI script {} is not executable", path.display());
+            return Err(Supernova::boom(&msg).with_code(response::Code::CgiError));
+        }
+
+        let path_info = if i + 1 < segments.len() {
+            format!("/{}", segments[i + 1..].join("/"))
+        } else {
+            String::new()
+        };
+
+        return Ok(Some(Script {
+            path,
+            script_name,
+            path_info,
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Builds the CGI environment for a request. SCRIPT_NAME and PATH_INFO come
+/// from whatever the request was routed to. `input` is the decoded query for
+/// paths configured to ask for input, and `client_cn` the subject CN of a
+/// client certificate verified against a CA.
+pub fn environment(
+    url: &Url,
+    remote_address: SocketAddr,
+    tls_conn: &ServerConnection,
+    script_name: &str,
+    path_info: &str,
+    input: Option<&str>,
+    client_cn: Option<&str>,
+) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", String::from("CGI/1.1")),
+        ("SERVER_PROTOCOL", String::from("GEMINI")),
+        ("SERVER_SOFTWARE", format!("laika/{}", LAIKA_VERSION)),
+        ("SERVER_NAME", url.host_str().unwrap_or("").to_string()),
+        (
+            "SERVER_PORT",
+            url.port().unwrap_or(crate::conf::GEMINI_PORT).to_string(),
+        ),
+        ("GEMINI_URL", url.to_string()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("QUERY_STRING", url.query().unwrap_or("").to_string()),
+        ("REMOTE_ADDR", remote_address.ip().to_string()),
+        ("REMOTE_HOST", remote_address.ip().to_string()),
+        ("REMOTE_PORT", remote_address.port().to_string()),
+        ("PATH", String::from("/usr/local/bin:/usr/bin:/bin")),
+    ];
+
+    if let Some(v) = input {
+        env.push(("GEMINI_INPUT", v.to_string()));
+    }
+    if let Some(v) = tls_conn.protocol_version() {
+        env.push(("TLS_VERSION", format!("{:?}", v).replace('_', ".")));
+    }
+    if let Some(v) = tls_conn.negotiated_cipher_suite() {
+        env.push(("TLS_CIPHER", format!("{:?}", v.suite())));
+    }
+    if let Some(cert) = tls_conn.peer_certificates().and_then(|c| c.first()) {
+        env.push(("AUTH_TYPE", String::from("CERTIFICATE")));
+        env.push((
+            "TLS_CLIENT_HASH",
+            format!("SHA256:{}", tls::fingerprint(cert)),
+        ));
+    }
+    if let Some(v) = client_cn {
+        env.push(("REMOTE_USER", v.to_string()));
+    }
+
+    env.into_iter().map(|(k, v)| (k.to_string(), v)).collect()
+}
+
+/// What a script writes to stdout after its status line. Reading it fails
+/// once the script has run past its timeout or written more than
+/// `max_output` bytes. Dropping it kills the script.
+pub struct Output {
+    stdout: BufReader<ChildStdout>,
+    child: Option<Child>,
+    script_path: String,
+    deadline: Pin<Box<Sleep>>,
+    remaining: u64,
+}
+
+impl AsyncRead for Output {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "CGI script timed out",
+            )));
+        }
+
+        // nothing may land in `buf` if the read turns out to be an error
+        let mut chunk = [0; 8192];
+        let len = chunk.len().min(buf.remaining());
+        let mut read = ReadBuf::new(&mut chunk[..len]);
+        ready!(Pin::new(&mut this.stdout).poll_read(cx, &mut read))?;
+        let n = read.filled().len() as u64;
+        if n == 0 && len > 0 {
+            // stdout is closed, so the script is done or about to be
+            if let Some(child) = this.child.take() {
+                let deadline = this.deadline.deadline();
+                tokio::spawn(log_exit(child, this.script_path.clone(), deadline));
+            }
+        }
+        if n > this.remaining {
+            return Poll::Ready(Err(io::Error::other(
+                "CGI script output exceeds max_output",
+            )));
+        }
+        this.remaining -= n;
+        buf.put_slice(read.filled());
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Starts a script and reads the status line it has to begin its output
+/// with. The rest of its output is returned for relaying to the client as
+/// it arrives. A script that doesn't write a status line within the
+/// timeout is a CGI error; once it has, failures can only cut the response
+/// short.
+pub async fn start(
+    conf: &CgiConf,
+    script: &Script,
+    env: Vec<(String, String)>,
+) -> Result<(Vec<u8>, Output), Supernova> {
+    let cgi_err = |msg: String| Supernova::boom(&msg).with_code(response::Code::CgiError);
+    let script_path = script.path().display().to_string();
+
+    let mut cmd = Command::new(script.path());
+    cmd.env_clear()
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(dir) = script.path().parent() {
+        cmd.current_dir(dir);
+    }
+
+    let mut child = match cmd.spawn() {
+        Ok(v) => v,
+        Err(e) => return Err(cgi_err(format!("could not run {}: {}", script_path, e))),
+    };
+
+    let (stdout, stderr) = match (child.stdout.take(), child.stderr.take()) {
+        (Some(o), Some(e)) => (o, e),
+        _ => return Err(cgi_err(format!("no output pipes for {}", script_path))),
+    };
+
+    let deadline = Instant::now() + conf.timeout();
+    tokio::spawn(log_stderr(stderr, script_path.clone(), deadline));
+
+    let mut stdout = BufReader::new(stdout);
+    let header = match time::timeout_at(deadline, response::read_status_line(&mut stdout)).await {
+        Ok(Ok(Some(v))) => v,
+        Ok(Ok(None)) => {
+            // stdout closing usually beats the exit by a moment
+            let msg = match time::timeout_at(deadline, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => {
+                    format!("{} exited with {}", script_path, status)
+                }
+                _ => format!(
+                    "{} did not start its output with a status line",
+                    script_path
+                ),
+            };
+            return Err(cgi_err(msg));
+        }
+        Ok(Err(e)) => {
+            let msg = format!("could not read output of {}: {}", script_path, e);
+            return Err(cgi_err(msg));
+        }
+        Err(_) => {
+            let msg = format!("{} timed out after {}s", script_path, conf.timeout);
+            return Err(cgi_err(msg));
+        }
+    };
+
+    let output = Output {
+        stdout,
+        child: Some(child),
+        script_path,
+        deadline: Box::pin(time::sleep_until(deadline)),
+        remaining: conf.max_output,
+    };
+
+    Ok((header, output))
+}
+
+// Waits for a script whose output has been relayed and logs a failing
+// exit. One still running at the deadline is killed when dropped.
+async fn log_exit(mut child: Child, script_path: String, deadline: Instant) {
+    match time::timeout_at(deadline, child.wait()).await {
+        Ok(Ok(status)) if !status.success() => {
+            log::error!("CGI {} :: exited with {}", script_path, status);
+        }
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::error!("CGI {} :: could not wait for exit: {}", script_path, e),
+        Err(_) => log::error!("CGI {} :: still running after the timeout", script_path),
+    }
+}
+
+// Drains a script's stderr so it never blocks on a full pipe, and logs the
+// start of it.
+async fn log_stderr(mut stderr: ChildStderr, script_path: String, deadline: Instant) {
+    let mut kept = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = match time::timeout_at(deadline, stderr.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            _ => break,
+        };
+        let room = MAX_STDERR_BYTES - kept.len();
+        kept.extend_from_slice(&buf[..n.min(room)]);
+    }
+
+    if !kept.is_empty() {
+        log::warn!(
+            "CGI {} :: stderr: {}",
+            script_path,
+            String::from_utf8_lossy(&kept).trim_end()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn script_matching() {
+        let mut conf = CgiConf {
+            paths: vec![String::from("/cgi-bin/")],
+            patterns: vec![String::from("*.cgi"), String::from("/apps/*.py")],
+            ..Default::default()
+        };
+        conf.load().unwrap();
+        assert!(conf.is_script("/cgi-bin/anything"));
+        assert!(conf.is_script("/blog/search.cgi"));
+        assert!(conf.is_script("/apps/hello.py"));
+        assert!(!conf.is_script("/cgi-binary"));
+        assert!(!conf.is_script("/blog/index.gmi"));
+        assert!(!conf.is_script("/blog/hello.py"));
+        // * stops at a slash
+        assert!(!conf.is_script("/apps/sub/hello.py"));
+
+        let mut bad = CgiConf {
+            patterns: vec![String::from("[")],
+            ..Default::default()
+        };
+        assert!(bad.load().is_err());
+    }
+
+    async fn write_script(path: &Path, body: &str) {
+        std::fs::write(path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn find_and_run() {
+        let root = std::env::temp_dir().join(format!("laika-cgi-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("cgi-bin")).unwrap();
+        write_script(
+            &root.join("cgi-bin/env"),
+            "printf '20 text/plain\\r\\n%s|%s|%s' \"$SCRIPT_NAME\" \"$PATH_INFO\" \"$X\"",
+        )
+        .await;
+        write_script(&root.join("cgi-bin/fail"), "exit 3").await;
+        write_script(&root.join("cgi-bin/slow"), "sleep 5").await;
+        write_script(&root.join("cgi-bin/loud"), "head -c 100 /dev/zero").await;
+        write_script(
+            &root.join("cgi-bin/chatty"),
+            "head -c 1000000 /dev/zero >&2; printf '20 text/plain\\r\\nok'",
+        )
+        .await;
+        write_script(
+            &root.join("cgi-bin/long"),
+            "printf '20 text/plain\\r\\n'; head -c 100 /dev/zero",
+        )
+        .await;
+        write_script(
+            &root.join("cgi-bin/stall"),
+            "printf '20 text/plain\\r\\n'; sleep 5",
+        )
+        .await;
+        std::fs::write(root.join("static.gmi"), "hi").unwrap();
+
+        let conf = CgiConf {
+            paths: vec![String::from("/cgi-bin")],
+            timeout: 1,
+            max_output: 64,
+            ..Default::default()
+        };
+
+        let script = find_script(&conf, &root, "", "/cgi-bin/env/a/b")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(script.script_name(), "/cgi-bin/env");
+        assert_eq!(script.path_info(), "/a/b");
+
+        // configured paths are request paths, so they don't reach into
+        // user directories mounted elsewhere
+        assert_eq!(
+            find_script(&conf, &root, "/~bob", "/cgi-bin/env")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let env = vec![
+            (
+                String::from("SCRIPT_NAME"),
+                script.script_name().to_string(),
+            ),
+            (String::from("PATH_INFO"), script.path_info().to_string()),
+            (String::from("X"), String::from("x")),
+        ];
+        let (header, mut body) = start(&conf, &script, env).await.unwrap();
+        let mut out = Vec::new();
+        body.read_to_end(&mut out).await.unwrap();
+        assert_eq!(header, b"20 text/plain\r\n");
+        assert_eq!(out, b"/cgi-bin/env|/a/b|x");
+
+        // a flood on stderr doesn't hold up stdout
+        let script = find_script(&conf, &root, "", "/cgi-bin/chatty")
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, mut body) = start(&conf, &script, Vec::new()).await.unwrap();
+        let mut out = Vec::new();
+        body.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, b"ok");
+
+        // past the status line, going over the limits cuts the body off
+        for name in ["/cgi-bin/long", "/cgi-bin/stall"] {
+            let script = find_script(&conf, &root, "", name).await.unwrap().unwrap();
+            let (_, mut body) = start(&conf, &script, Vec::new()).await.unwrap();
+            let mut out = Vec::new();
+            assert!(body.read_to_end(&mut out).await.is_err(), "{}", name);
+        }
+
+        assert_eq!(
+            find_script(&conf, &root, "", "/static.gmi").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            find_script(&conf, &root, "", "/cgi-bin/missing")
+                .await
+                .unwrap(),
+            None
+        );
+
+        for name in ["/cgi-bin/fail", "/cgi-bin/slow", "/cgi-bin/loud"] {
+            let script = find_script(&conf, &root, "", name).await.unwrap().unwrap();
+            let err = start(&conf, &script, Vec::new()).await.err().unwrap();
+            assert_eq!(err.code(), response::Code::CgiError, "{}", name);
+        }
+
+        // the exit status is reported even when stdout closes first
+        let script = find_script(&conf, &root, "", "/cgi-bin/fail")
+            .await
+            .unwrap()
+            .unwrap();
+        let err = start(&conf, &script, Vec::new()).await.err().unwrap();
+        assert!(err.to_string().contains("exit status: 3"), "{}", err);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,421 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerifier, UnparsedCertRevocationList,
+};
+use tokio_rustls::rustls::{self, Certificate, CertificateError, RootCertStore};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::FromDer;
+
+use crate::conf;
+use crate::err::Supernova;
+use crate::response;
+use crate::routes::Route;
+use crate::tls;
+
+/// A path prefix that needs a client certificate.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CertRoute {
+    path: String,
+    // SHA-256 fingerprints of the certificates let in. when empty, any
+    // valid certificate will do
+    #[serde(default)]
+    fingerprints: Vec<String>,
+    // PEM bundle of CA certificates. certificates they issued are let in
+    // without being listed
+    #[serde(default)]
+    ca_bundle: Option<PathBuf>,
+    // PEM or DER revocation list checked along with ca_bundle
+    #[serde(default)]
+    crl: Option<PathBuf>,
+    #[serde(skip)]
+    verifier: Option<CaVerifier>,
+}
+
+// Built from ca_bundle and crl when the config is loaded.
+#[derive(Clone)]
+struct CaVerifier(Arc<AllowAnyAuthenticatedClient>);
+
+impl fmt::Debug for CaVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CaVerifier")
+    }
+}
+
+impl CertRoute {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if !self.path.starts_with('/') {
+            let msg = format!("client certificate path must start with /: {}", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+
+        for fp in self.allowed() {
+            if fp.len() != 64 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                let msg = format!(
+                    "client certificate fingerprint is not a SHA-256 hex digest: {}",
+                    fp
+                );
+                return Err(Supernova::boom(&msg));
+            }
+        }
+
+        if self.crl.is_some() && self.ca_bundle.is_none() {
+            let msg = format!("client certificate crl for {} needs a ca_bundle", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+
+        Ok(())
+    }
+
+    /// Validates the route and reads its CA bundle and revocation list.
+    pub fn load(&mut self) -> Result<(), Supernova> {
+        self.validate()?;
+
+        let ca_bundle = match &self.ca_bundle {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let mut roots = RootCertStore::empty();
+        for cert in conf::load_certs(ca_bundle)? {
+            if let Err(e) = roots.add(&cert) {
+                let msg = format!("Unusable CA certificate in {}: {}", ca_bundle.display(), e);
+                return Err(Supernova::boom(&msg));
+            }
+        }
+        if roots.is_empty() {
+            let msg = format!("No CA certificates in {}", ca_bundle.display());
+            return Err(Supernova::boom(&msg));
+        }
+
+        let crls = match &self.crl {
+            Some(path) => load_crls(path)?,
+            None => Vec::new(),
+        };
+        let verifier = match AllowAnyAuthenticatedClient::new(roots).with_crls(crls) {
+            Ok(v) => v,
+            Err(e) => {
+                let msg = format!(
+                    "Unusable certificate revocation list {}: {:?}",
+                    self.crl.as_deref().unwrap_or(ca_bundle).display(),
+                    e
+                );
+                return Err(Supernova::boom(&msg));
+            }
+        };
+
+        self.verifier = Some(CaVerifier(Arc::new(verifier)));
+        Ok(())
+    }
+
+    // Same form as proxy fingerprints: lowercase hex, separators dropped.
+    fn allowed(&self) -> impl Iterator<Item = String> + '_ {
+        self.fingerprints
+            .iter()
+            .map(|fp| fp.replace(':', "").to_ascii_lowercase())
+    }
+}
+
+impl Route for CertRoute {
+    fn path(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+}
+
+// A CRL file holds either PEM blocks or a single DER list.
+fn load_crls(path: &Path) -> Result<Vec<UnparsedCertRevocationList>, Supernova> {
+    let bytes = match fs::read(path) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!(
+                "Could not read certificate revocation list {}: {}",
+                path.display(),
+                e
+            );
+            return Err(Supernova::boom(&msg));
+        }
+    };
+
+    let crls = match rustls_pemfile::crls(&mut io::BufReader::new(bytes.as_slice())) {
+        Ok(v) if !v.is_empty() => v,
+        _ => vec![bytes],
+    };
+
+    Ok(crls.into_iter().map(UnparsedCertRevocationList).collect())
+}
+
+/// Checks the certificate chain a client sent against `route`. Clients
+/// without one get 60, certificates that are expired, not yet valid or
+/// don't parse get 62, and ones that are neither on the allowlist nor
+/// issued by the route's CA get 61. Returns the subject CN of certificates
+/// that were verified against the CA.
+pub fn check(
+    route: &CertRoute,
+    peer_certs: Option<&[Certificate]>,
+) -> Result<Option<String>, Supernova> {
+    let (cert, intermediates) = match peer_certs.and_then(|c| c.split_first()) {
+        Some(v) => v,
+        None => {
+            let msg = format!("no client certificate for {}", route.path());
+            return Err(Supernova::boom(&msg).with_code(response::Code::ClientCertificateRequired));
+        }
+    };
+
+    let fingerprint = tls::fingerprint(cert);
+
+    let parsed = match X509Certificate::from_der(&cert.0) {
+        Ok((_, v)) => v,
+        Err(e) => {
+            let msg = format!("could not parse client certificate {}: {}", fingerprint, e);
+            return Err(Supernova::boom(&msg).with_code(response::Code::CertificateNotValid));
+        }
+    };
+    if !parsed.validity().is_valid() {
+        let msg = format!(
+            "client certificate {} is expired or not yet valid",
+            fingerprint
+        );
+        return Err(Supernova::boom(&msg).with_code(response::Code::CertificateNotValid));
+    }
+
+    if route.allowed().any(|fp| fp == fingerprint) {
+        return Ok(None);
+    }
+
+    let verifier = match &route.verifier {
+        Some(v) => v,
+        None if route.fingerprints.is_empty() => return Ok(None),
+        None => {
+            let msg = format!(
+                "client certificate {} is not allowed for {}",
+                fingerprint,
+                route.path()
+            );
+            return Err(Supernova::boom(&msg).with_code(response::Code::CertificateNotAuthorised));
+        }
+    };
+
+    if let Err(e) = verifier
+        .0
+        .verify_client_cert(cert, intermediates, SystemTime::now())
+    {
+        let code = match e {
+            rustls::Error::InvalidCertificate(
+                CertificateError::Expired
+                | CertificateError::NotValidYet
+                | CertificateError::Revoked
+                | CertificateError::BadEncoding,
+            ) => response::Code::CertificateNotValid,
+            _ => response::Code::CertificateNotAuthorised,
+        };
+        let msg = format!(
+            "client certificate {} failed verification for {}: {}",
+            fingerprint,
+            route.path(),
+            e
+        );
+        return Err(Supernova::boom(&msg).with_code(code));
+    }
+
+    let cn = parsed
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(String::from);
+
+    Ok(cn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+
+    use time::{Duration, OffsetDateTime};
+
+    fn cert(not_before: OffsetDateTime, not_after: OffsetDateTime) -> Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![String::from("client")]);
+        params.not_before = not_before;
+        params.not_after = not_after;
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        Certificate(cert.serialize_der().unwrap())
+    }
+
+    fn route(path: &str, fingerprints: &[&str]) -> CertRoute {
+        CertRoute {
+            path: path.to_string(),
+            fingerprints: fingerprints.iter().map(|f| f.to_string()).collect(),
+            ca_bundle: None,
+            crl: None,
+            verifier: None,
+        }
+    }
+
+    fn client(name: &str, serial: u64, ca: &rcgen::Certificate) -> Certificate {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        params.serial_number = Some(rcgen::SerialNumber::from(serial));
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        Certificate(cert.serialize_der_with_signer(ca).unwrap())
+    }
+
+    #[test]
+    fn routes() {
+        let routes = vec![route("/private/", &[]), route("/private/admin", &[])];
+        assert_eq!(
+            routes::find(&routes, "/private/admin/x").unwrap().path(),
+            "/private/admin"
+        );
+        assert_eq!(
+            routes::find(&routes, "/private").unwrap().path(),
+            "/private"
+        );
+        assert!(routes::find(&routes, "/privateer").is_none());
+
+        assert!(route("private", &[]).validate().is_err());
+        assert!(route("/a", &["abc"]).validate().is_err());
+        assert!(route("/a", &[&"AB:".repeat(32)]).validate().is_ok());
+    }
+
+    #[test]
+    fn checks() {
+        let now = OffsetDateTime::now_utc();
+        let good = cert(now - Duration::days(1), now + Duration::days(1));
+        let expired = cert(now - Duration::days(2), now - Duration::days(1));
+        let future = cert(now + Duration::days(1), now + Duration::days(2));
+        let fp = tls::fingerprint(&good).to_ascii_uppercase();
+
+        let anyone = route("/private", &[]);
+        let only = route("/private", &[&fp]);
+        let code = |route: &CertRoute, certs: Option<&[Certificate]>| {
+            check(route, certs).unwrap_err().code()
+        };
+
+        assert!(check(&anyone, Some(std::slice::from_ref(&good))).is_ok());
+        assert!(check(&only, Some(std::slice::from_ref(&good))).is_ok());
+
+        assert_eq!(
+            code(&anyone, None),
+            response::Code::ClientCertificateRequired
+        );
+        assert_eq!(
+            code(&anyone, Some(&[])),
+            response::Code::ClientCertificateRequired
+        );
+        assert_eq!(
+            code(&only, Some(&[expired])),
+            response::Code::CertificateNotValid
+        );
+        assert_eq!(
+            code(&anyone, Some(&[future])),
+            response::Code::CertificateNotValid
+        );
+        assert_eq!(
+            code(&anyone, Some(&[Certificate(b"junk".to_vec())])),
+            response::Code::CertificateNotValid
+        );
+        assert_eq!(
+            code(&route("/private", &[&"ab".repeat(32)]), Some(&[good])),
+            response::Code::CertificateNotAuthorised
+        );
+    }
+
+    #[test]
+    fn ca_verification() {
+        let dir = std::env::temp_dir().join(format!("laika-clientcert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "laika test CA");
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            rcgen::KeyUsagePurpose::KeyCertSign,
+            rcgen::KeyUsagePurpose::CrlSign,
+        ];
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+
+        let now = OffsetDateTime::now_utc();
+        let crl =
+            rcgen::CertificateRevocationList::from_params(rcgen::CertificateRevocationListParams {
+                this_update: now - Duration::days(1),
+                next_update: now + Duration::days(1),
+                crl_number: rcgen::SerialNumber::from(1u64),
+                issuing_distribution_point: None,
+                revoked_certs: vec![rcgen::RevokedCertParams {
+                    serial_number: rcgen::SerialNumber::from(2u64),
+                    revocation_time: now - Duration::days(1),
+                    reason_code: None,
+                    invalidity_date: None,
+                }],
+                alg: &rcgen::PKCS_ECDSA_P256_SHA256,
+                key_identifier_method: rcgen::KeyIdMethod::Sha256,
+            })
+            .unwrap();
+        std::fs::write(
+            dir.join("ca.crl"),
+            crl.serialize_der_with_signer(&ca).unwrap(),
+        )
+        .unwrap();
+
+        let mut ca_route = route("/internal", &[]);
+        ca_route.ca_bundle = Some(dir.join("ca.pem"));
+        ca_route.crl = Some(dir.join("ca.crl"));
+        ca_route.load().unwrap();
+
+        let alice = client("alice", 1, &ca);
+        assert_eq!(
+            check(&ca_route, Some(&[alice])).unwrap().as_deref(),
+            Some("alice")
+        );
+
+        let revoked = client("mallory", 2, &ca);
+        let err = check(&ca_route, Some(&[revoked])).unwrap_err();
+        assert_eq!(err.code(), response::Code::CertificateNotValid);
+
+        let now = OffsetDateTime::now_utc();
+        let stranger = cert(now - Duration::days(1), now + Duration::days(1));
+        let err = check(&ca_route, Some(std::slice::from_ref(&stranger))).unwrap_err();
+        assert_eq!(err.code(), response::Code::CertificateNotAuthorised);
+
+        // listing a fingerprint still lets a certificate in on its own
+        ca_route.fingerprints = vec![tls::fingerprint(&stranger)];
+        assert_eq!(check(&ca_route, Some(&[stranger])).unwrap(), None);
+
+        let mut broken = route("/internal", &[]);
+        broken.crl = Some(dir.join("ca.crl"));
+        assert!(broken.load().is_err());
+        broken.ca_bundle = Some(dir.join("ca.crl"));
+        assert!(broken.load().is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -22,6 +22,7 @@ use std::fmt::Formatter;
 pub struct Supernova {
     message: String,
     code: response::Code,
+    meta: String,
 }
 
 impl Error for Supernova {}
@@ -31,6 +32,7 @@ impl Supernova {
         Supernova {
             message: message.into(),
             code: response::Code::Unknown,
+            meta: String::new(),
         }
     }
 
@@ -42,6 +44,16 @@ impl Supernova {
         self.code = code;
         self.clone()
     }
+
+    // Text sent to the client after the status code.
+    pub fn meta(&self) -> &str {
+        &self.meta
+    }
+
+    pub fn with_meta(&mut self, meta: &str) -> Supernova {
+        self.meta = meta.into();
+        self.clone()
+    }
 }
 
 impl std::fmt::Display for Supernova {
@@ -61,6 +73,10 @@ mod tests {
 
         sn.with_code(response::Code::BadRequest);
         assert_eq!(sn.code(), response::Code::BadRequest);
+        assert_eq!(sn.meta(), "");
+
+        sn.with_meta("nope");
+        assert_eq!(sn.meta(), "nope");
 
         assert_eq!(format!("{}", sn), String::from("test"));
     }
@@ -14,53 +14,174 @@
  * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
  */
 
+use std::ffi::OsStr;
 use std::io;
+use std::path::{Path, PathBuf};
 
+use glob::MatchOptions;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use tokio::fs;
+use url::Url;
 
 use crate::err::Supernova;
 use crate::response;
 
-pub async fn get(path: &str) -> Result<(fs::File, String), Supernova> {
-    let metadata = match fs::metadata(path).await {
-        Ok(m) => m,
-        Err(e) => {
-            let code = match e.kind() {
-                io::ErrorKind::NotFound => response::Code::NotFound,
-                _ => response::Code::PermanentFailure,
-            };
+/// How configured globs match request paths: `*` and `?` don't match
+/// across a `/`.
+pub const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
 
-            let msg = format!("{}", e);
-            let wrapped_err = Supernova::boom(&msg).with_code(code);
+// Characters left alone when encoding a single path segment: RFC 3986
+// unreserved characters plus the sub-delims that are safe in a path.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'!')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')')
+    .remove(b'*')
+    .remove(b'+')
+    .remove(b',')
+    .remove(b';')
+    .remove(b'=')
+    .remove(b':')
+    .remove(b'@');
 
-            return Err(wrapped_err);
+/// Decodes the path of a request URL into an absolute, slash-separated
+/// UTF-8 path. Each segment is percent-decoded and dot segments are resolved
+/// before anything touches the filesystem, so `..` in any spelling can't
+/// climb above the root.
+pub fn normalise_path(url_path: &str) -> Result<String, Supernova> {
+    let mut segments: Vec<String> = Vec::new();
+
+    for raw in url_path.split('/') {
+        let seg = match percent_decode_str(raw).decode_utf8() {
+            Ok(v) => v,
+            Err(e) => {
+                let msg = format!("request path is not valid UTF-8: {}: {}", url_path, e);
+                return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+            }
+        };
+
+        // an encoded slash or NUL would change how the path splits into
+        // components once it reaches the OS
+        if seg.contains(['/', '\0']) {
+            let msg = format!("encoded slash or NUL in request path: {}", url_path);
+            return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
         }
-    };
 
-    let path = if metadata.is_dir() {
-        format!("{}/index.gmi", path)
-    } else {
-        path.to_string()
-    };
+        match seg.as_ref() {
+            "" | "." => continue,
+            ".." => {
+                if segments.pop().is_none() {
+                    let msg = format!("directory traversal attempted: {}", url_path);
+                    return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+                }
+            }
+            _ => segments.push(seg.into_owned()),
+        }
+    }
+
+    Ok(format!("/{}", segments.join("/")))
+}
+
+/// Maps a path from `normalise_path` onto `root`.
+pub fn local_path(root: &Path, req_path: &str) -> PathBuf {
+    root.join(req_path.trim_start_matches('/'))
+}
+
+/// Whether `req_path` is `prefix` or somewhere below it. Both are decoded
+/// request paths; a trailing slash on the prefix doesn't matter.
+pub fn path_under(req_path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match req_path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+        None => false,
+    }
+}
+
+/// Percent-encodes a file name so it can be used as one segment of a
+/// generated link.
+pub fn encode_segment(name: &str) -> String {
+    utf8_percent_encode(name, SEGMENT).to_string()
+}
+
+/// Resolves symlinks in `path` and makes sure the result is still inside
+/// `root`. Anything that escapes is reported as not found.
+pub async fn confine(root: &Path, path: &Path) -> Result<PathBuf, Supernova> {
+    let root = fs::canonicalize(root).await.map_err(wrap_io_err)?;
+    let path = fs::canonicalize(path).await.map_err(wrap_io_err)?;
+
+    if !path.starts_with(&root) {
+        let msg = format!("{} resolves outside of {}", path.display(), root.display());
+        return Err(Supernova::boom(&msg).with_code(response::Code::NotFound));
+    }
+
+    Ok(path)
+}
+
+/// Whether `path` is a directory inside `root`. Anything that can't be
+/// resolved is left for `get` to report.
+pub async fn is_dir(root: &Path, path: &Path) -> bool {
+    match confine(root, path).await {
+        Ok(v) => fs::metadata(v).await.map(|m| m.is_dir()).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Where to send a client whose `req_url` names the directory at
+/// `local_path` without a trailing slash, so relative links in its index
+/// or listing resolve inside it. The query is kept.
+pub async fn slash_redirect(req_url: &Url, root: &Path, local_path: &Path) -> Option<Url> {
+    let path = req_url.path();
+    if path.ends_with('/') || !is_dir(root, local_path).await {
+        return None;
+    }
 
-    let fd = match fs::File::open(&path).await {
-        Ok(fd) => fd,
-        Err(e) => {
-            let code = match e.kind() {
-                io::ErrorKind::NotFound => response::Code::NotFound,
-                _ => response::Code::PermanentFailure,
-            };
+    let mut url = req_url.clone();
+    url.set_path(&format!("{}/", path));
+    Some(url)
+}
+
+/// What a request path turned out to point at.
+pub enum Resource {
+    File {
+        fd: fs::File,
+        mime: String,
+    },
+    /// A directory with no index file in it.
+    Directory(PathBuf),
+}
 
-            let msg = format!("{}", e);
-            let wrapped_err = Supernova::boom(&msg).with_code(code);
+pub async fn get(root: &Path, path: &Path, index_file_name: &str) -> Result<Resource, Supernova> {
+    let path = confine(root, path).await?;
+    let metadata = fs::metadata(&path).await.map_err(wrap_io_err)?;
 
-            return Err(wrapped_err);
+    let path = if metadata.is_dir() {
+        let index = path.join(index_file_name);
+        match fs::symlink_metadata(&index).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Resource::Directory(path));
+            }
+            _ => confine(root, &index).await?,
         }
+    } else {
+        path
     };
 
-    let mime = match tree_magic_mini::from_filepath(path.as_ref()) {
+    let fd = fs::File::open(&path).await.map_err(wrap_io_err)?;
+
+    let mime = match tree_magic_mini::from_filepath(&path) {
         Some(m) => {
-            if path.ends_with(".gmi") {
+            if path.extension() == Some(OsStr::new("gmi")) {
                 response::GEMINI_MIME
             } else {
                 m
@@ -69,5 +190,194 @@ pub async fn get(path: &str) -> Result<(fs::File, String), Supernova> {
         None => response::GEMINI_MIME,
     };
 
-    Ok((fd, mime.to_string()))
+    Ok(Resource::File {
+        fd,
+        mime: mime.to_string(),
+    })
+}
+
+pub fn wrap_io_err(e: io::Error) -> Supernova {
+    let code = match e.kind() {
+        io::ErrorKind::NotFound => response::Code::NotFound,
+        _ => response::Code::PermanentFailure,
+    };
+
+    let msg = format!("{}", e);
+    Supernova::boom(&msg).with_code(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn local_path_plain() {
+        let root = Path::new("/srv/gemini");
+        assert_eq!(local_path(root, "/"), root);
+        assert_eq!(
+            local_path(root, "/a/b.gmi"),
+            Path::new("/srv/gemini/a/b.gmi")
+        );
+    }
+
+    #[test]
+    fn normalise_path_plain() {
+        assert_eq!(normalise_path("").unwrap(), "/");
+        assert_eq!(normalise_path("/").unwrap(), "/");
+        assert_eq!(normalise_path("/a/b.gmi").unwrap(), "/a/b.gmi");
+        assert_eq!(normalise_path("//a///b/").unwrap(), "/a/b");
+    }
+
+    #[test]
+    fn normalise_path_decodes_utf8() {
+        assert_eq!(normalise_path("/my%20notes.gmi").unwrap(), "/my notes.gmi");
+        assert_eq!(normalise_path("/caf%C3%A9.gmi").unwrap(), "/café.gmi");
+        assert_eq!(normalise_path("/100%25.gmi").unwrap(), "/100%.gmi");
+
+        // lone continuation byte, overlong encoding of '/'
+        for path in ["/caf%E9.gmi", "/a%C0%AFb"] {
+            let err = normalise_path(path).unwrap_err();
+            assert_eq!(err.code(), response::Code::BadRequest, "{}", path);
+        }
+    }
+
+    #[test]
+    fn prefix_matching() {
+        assert!(path_under("/a/b", "/a"));
+        assert!(path_under("/a/b", "/a/"));
+        assert!(path_under("/a", "/a/"));
+        assert!(path_under("/a/b", "/"));
+        assert!(!path_under("/ab", "/a"));
+        assert!(!path_under("/b/a", "/a"));
+    }
+
+    #[test]
+    fn segment_encoding() {
+        assert_eq!(encode_segment("index.gmi"), "index.gmi");
+        assert_eq!(encode_segment("my notes.gmi"), "my%20notes.gmi");
+        assert_eq!(encode_segment("café.gmi"), "caf%C3%A9.gmi");
+        assert_eq!(encode_segment("a/b?c#d%"), "a%2Fb%3Fc%23d%25");
+        assert_eq!(encode_segment("~user's (copy)"), "~user's%20(copy)");
+    }
+
+    #[test]
+    fn normalise_path_dot_segments() {
+        assert_eq!(normalise_path("/a/./b/../c").unwrap(), "/a/c");
+        assert_eq!(normalise_path("/a/%2e%2E/c").unwrap(), "/c");
+        assert_eq!(normalise_path("/a/..%2e/c").unwrap(), "/a/.../c");
+        // dots that aren't a whole segment are just part of a name
+        assert_eq!(normalise_path("/..a/b..").unwrap(), "/..a/b..");
+    }
+
+    #[test]
+    fn normalise_path_escapes() {
+        for path in [
+            "/..",
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/%2e%2e/etc/passwd",
+            "/.%2E/etc/passwd",
+            "/a/%2e%2e/%2e%2e/etc",
+        ] {
+            let err = normalise_path(path).unwrap_err();
+            assert_eq!(err.code(), response::Code::BadRequest, "{}", path);
+        }
+    }
+
+    #[test]
+    fn normalise_path_encoded_separators() {
+        for path in ["/..%2fetc/passwd", "/a%2F..%2F..%2Fetc", "/a%00.gmi"] {
+            let err = normalise_path(path).unwrap_err();
+            assert_eq!(err.code(), response::Code::BadRequest, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn slash_redirects() {
+        let root = std::env::temp_dir().join(format!("laika-slash-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("blog")).unwrap();
+        std::fs::create_dir_all(root.join("users/alice")).unwrap();
+        std::fs::write(root.join("a.gmi"), "a").unwrap();
+
+        async fn redirect(url: &str, root: &Path, req_path: &str) -> Option<String> {
+            let url = Url::parse(url).unwrap();
+            let local = local_path(root, req_path);
+            slash_redirect(&url, root, &local).await.map(String::from)
+        }
+
+        assert_eq!(
+            redirect("gemini://host", &root, "").await.as_deref(),
+            Some("gemini://host/")
+        );
+        assert_eq!(
+            redirect("gemini://host/blog", &root, "/blog")
+                .await
+                .as_deref(),
+            Some("gemini://host/blog/")
+        );
+        assert_eq!(
+            redirect("gemini://host/blog?q=1", &root, "/blog")
+                .await
+                .as_deref(),
+            Some("gemini://host/blog/?q=1")
+        );
+        assert_eq!(redirect("gemini://host/blog/", &root, "/blog/").await, None);
+        assert_eq!(redirect("gemini://host/a.gmi", &root, "/a.gmi").await, None);
+        assert_eq!(redirect("gemini://host/nope", &root, "/nope").await, None);
+
+        // a user directory has its own root, and an empty path in it
+        let alice = root.join("users/alice");
+        assert_eq!(
+            redirect("gemini://host/~alice", &alice, "")
+                .await
+                .as_deref(),
+            Some("gemini://host/~alice/")
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn confine_symlinks() {
+        let base = std::env::temp_dir().join(format!("laika-confine-{}", std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("dir/inside.gmi"), "in").unwrap();
+        std::fs::write(outside.join("secret.gmi"), "out").unwrap();
+        symlink(root.join("dir/inside.gmi"), root.join("link-in.gmi")).unwrap();
+        symlink(outside.join("secret.gmi"), root.join("link-out.gmi")).unwrap();
+        symlink(&outside, root.join("dir-out")).unwrap();
+
+        assert!(confine(&root, &root.join("dir/inside.gmi")).await.is_ok());
+        assert!(confine(&root, &root.join("link-in.gmi")).await.is_ok());
+
+        let err = confine(&root, &root.join("link-out.gmi"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), response::Code::NotFound);
+
+        let err = confine(&root, &root.join("dir-out/secret.gmi"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), response::Code::NotFound);
+
+        let err = confine(&root, &root.join("missing.gmi")).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::NotFound);
+
+        assert!(is_dir(&root, &root.join("dir")).await);
+        assert!(!is_dir(&root, &root.join("dir/inside.gmi")).await);
+        assert!(!is_dir(&root, &root.join("dir-out")).await);
+
+        // an index file that links out of the root is refused too
+        std::fs::create_dir_all(root.join("sneaky")).unwrap();
+        symlink(outside.join("secret.gmi"), root.join("sneaky/index.gmi")).unwrap();
+        let err = get(&root, &root.join("sneaky"), "index.gmi").await;
+        assert_eq!(err.err().unwrap().code(), response::Code::NotFound);
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
 }
@@ -0,0 +1,200 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+use crate::err::Supernova;
+use crate::file;
+use crate::response;
+
+/// A file next to removed content, named after it with this appended,
+/// marks that content as gone. Its first line, if any, is sent as meta.
+pub const TOMBSTONE_SUFFIX: &str = ".gone";
+
+// How much of a tombstone file is read looking for the meta line.
+const MAX_TOMBSTONE_BYTES: u64 = 1024;
+
+/// Content that was removed on purpose.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GonePath {
+    // a request path, which covers everything below it too, or a glob
+    path: String,
+    // sent to the client after 52
+    #[serde(default)]
+    meta: Option<String>,
+    // compiled from path when the config is loaded
+    #[serde(skip)]
+    pattern: Option<glob::Pattern>,
+}
+
+impl GonePath {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if !self.path.starts_with('/') {
+            let msg = format!("gone path must start with /: {}", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+        if let Some(meta) = &self.meta {
+            if meta.contains(['\r', '\n']) {
+                let msg = format!("gone meta for {} must be a single line", self.path);
+                return Err(Supernova::boom(&msg));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validates the path and compiles it as a glob.
+    pub fn load(&mut self) -> Result<(), Supernova> {
+        self.validate()?;
+
+        match glob::Pattern::new(&self.path) {
+            Ok(v) => self.pattern = Some(v),
+            Err(e) => {
+                let msg = format!("Invalid gone pattern {}: {}", self.path, e);
+                return Err(Supernova::boom(&msg));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, req_path: &str) -> bool {
+        file::path_under(req_path, &self.path)
+            || self
+                .pattern
+                .as_ref()
+                .is_some_and(|p| p.matches_with(req_path, file::MATCH_OPTIONS))
+    }
+}
+
+/// The 52 for `req_path` if it's listed as gone.
+pub fn check(gone: &[GonePath], req_path: &str) -> Result<(), Supernova> {
+    match gone.iter().find(|g| g.matches(req_path)) {
+        Some(g) => Err(error(req_path, g.meta.as_deref().unwrap_or(""))),
+        None => Ok(()),
+    }
+}
+
+/// Whether `name` is a tombstone file, which is never served itself.
+pub fn is_tombstone(name: &str) -> bool {
+    name.ends_with(TOMBSTONE_SUFFIX)
+}
+
+/// Looks for a tombstone next to `path`, which wasn't found under `root`,
+/// and returns the 52 for it if there is one.
+pub async fn tombstone(root: &Path, path: &Path, req_path: &str) -> Option<Supernova> {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(TOMBSTONE_SUFFIX);
+
+    let marker = file::confine(root, Path::new(&marker)).await.ok()?;
+    let fd = fs::File::open(&marker).await.ok()?;
+    if !fd.metadata().await.ok()?.is_file() {
+        return None;
+    }
+
+    let mut buf = Vec::new();
+    if let Err(e) = fd.take(MAX_TOMBSTONE_BYTES).read_to_end(&mut buf).await {
+        log::error!("could not read tombstone {}: {}", marker.display(), e);
+    }
+    let text = String::from_utf8_lossy(&buf);
+    let meta = text.lines().next().unwrap_or("").trim();
+
+    Some(error(req_path, meta))
+}
+
+fn error(req_path: &str, meta: &str) -> Supernova {
+    let msg = format!("{} is gone", req_path);
+    Supernova::boom(&msg)
+        .with_code(response::Code::Gone)
+        .with_meta(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gone(path: &str, meta: Option<&str>) -> GonePath {
+        GonePath {
+            path: path.to_string(),
+            meta: meta.map(String::from),
+            pattern: None,
+        }
+    }
+
+    fn loaded(path: &str, meta: Option<&str>) -> GonePath {
+        let mut g = gone(path, meta);
+        g.load().unwrap();
+        g
+    }
+
+    #[test]
+    fn configured_paths() {
+        let list = vec![
+            loaded("/old", Some("moved to the archive")),
+            loaded("/drafts/*.gmi", None),
+        ];
+
+        let err = check(&list, "/old/post.gmi").unwrap_err();
+        assert_eq!(err.code(), response::Code::Gone);
+        assert_eq!(err.meta(), "moved to the archive");
+        assert!(check(&list, "/old").is_err());
+        assert_eq!(check(&list, "/drafts/a.gmi").unwrap_err().meta(), "");
+
+        assert!(check(&list, "/older").is_ok());
+        assert!(check(&list, "/drafts/a.txt").is_ok());
+        assert!(check(&list, "/drafts/a/b.gmi").is_ok());
+
+        assert!(gone("old", None).validate().is_err());
+        assert!(gone("/[", None).load().is_err());
+        assert!(gone("/a", Some("x\r\n20 text/gemini")).validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn tombstones() {
+        let root = std::env::temp_dir().join(format!("laika-gone-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("post.gmi.gone"), "retracted\nsecond line").unwrap();
+        std::fs::write(root.join("quiet.gmi.gone"), "").unwrap();
+        std::fs::create_dir_all(root.join("dir.gmi.gone")).unwrap();
+
+        let err = tombstone(&root, &root.join("post.gmi"), "/post.gmi")
+            .await
+            .unwrap();
+        assert_eq!(err.code(), response::Code::Gone);
+        assert_eq!(err.meta(), "retracted");
+
+        let err = tombstone(&root, &root.join("quiet.gmi"), "/quiet.gmi")
+            .await
+            .unwrap();
+        assert_eq!(err.meta(), "");
+
+        assert!(tombstone(&root, &root.join("missing.gmi"), "/missing.gmi")
+            .await
+            .is_none());
+        assert!(tombstone(&root, &root.join("dir.gmi"), "/dir.gmi")
+            .await
+            .is_none());
+
+        assert!(is_tombstone("post.gmi.gone"));
+        assert!(!is_tombstone("post.gmi"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
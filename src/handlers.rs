@@ -16,17 +16,36 @@
 
 use std::net::SocketAddr;
 use std::str;
+use std::time::Duration;
 
+use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
+use tokio::time;
 use tokio_rustls::server::TlsStream;
 use url::Url;
 
-use crate::conf::Conf;
+use crate::cgi;
+use crate::clientcert;
+use crate::conf::{self, Conf};
 use crate::err::Supernova;
 use crate::file;
+use crate::gone;
+use crate::input;
+use crate::listing;
+use crate::proxy;
+use crate::redirect;
 use crate::response;
+use crate::routes::{self, Route};
+use crate::scgi;
+use crate::userdir;
+
+// Maximum length of a request URL, not counting the trailing CRLF.
+const MAX_REQUEST_BYTES: usize = 1024;
+
+// Longest a refused connection is given to send its request.
+const SHED_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub async fn flush_and_kill(stream: &mut TlsStream<TcpStream>, remote_address: SocketAddr) {
     if let Err(e) = stream.flush().await {
@@ -41,21 +60,91 @@ pub async fn flush_and_kill(stream: &mut TlsStream<TcpStream>, remote_address: S
     };
 }
 
+/// Answers a connection that was refused after the handshake with the
+/// refusal's status, once the client has sent its request, and closes it.
+pub async fn shed(
+    conf: &Conf,
+    stream: &mut TlsStream<TcpStream>,
+    remote_address: SocketAddr,
+    refusal: &Supernova,
+) {
+    // reading the request first means closing doesn't reset the connection
+    // before the client sees the answer
+    let wait = conf.request_timeout().min(SHED_TIMEOUT);
+    let _ = time::timeout(wait, read_request(stream)).await;
+
+    let header = refusal.code().get_header(refusal.meta());
+    if let Err(e) = stream.write_all(&header).await {
+        log::error!("REQ {} :: {}", remote_address, e);
+    }
+    flush_and_kill(stream, remote_address).await;
+}
+
+// Reads a single CRLF-terminated request line, buffering across reads
+// until the terminator shows up. The returned bytes don't include the CRLF.
+pub async fn read_request<R>(reader: &mut R) -> Result<Vec<u8>, Supernova>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut req_buf: Vec<u8> = Vec::with_capacity(MAX_REQUEST_BYTES + 2);
+    let mut chunk: [u8; 1024] = [0; 1024];
+
+    loop {
+        let n = match reader.read(&mut chunk).await {
+            Ok(n) => n,
+            Err(e) => {
+                let msg = format!("failed to read from socket: {}", e);
+                return Err(Supernova::boom(&msg));
+            }
+        };
+
+        if n == 0 {
+            let msg = if req_buf.is_empty() {
+                String::from("connection closed before request was sent")
+            } else {
+                String::from("request was not terminated with CRLF")
+            };
+            return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+        }
+
+        // start looking one byte back in case the \r\n straddles two reads
+        let search_from = req_buf.len().saturating_sub(1);
+        req_buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(i) = req_buf[search_from..].windows(2).position(|w| w == b"\r\n") {
+            let end = search_from + i;
+            if end > MAX_REQUEST_BYTES {
+                let msg = format!("request exceeds {} bytes", MAX_REQUEST_BYTES);
+                return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+            }
+            req_buf.truncate(end);
+            return Ok(req_buf);
+        }
+
+        if req_buf.len() > MAX_REQUEST_BYTES + 1 {
+            let msg = format!("request exceeds {} bytes", MAX_REQUEST_BYTES);
+            return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+        }
+    }
+}
+
 pub async fn entrance(
+    conf: &Conf,
     stream: &mut TlsStream<TcpStream>,
     remote_address: SocketAddr,
 ) -> Result<Url, Supernova> {
-    let mut req_buf: [u8; 1024] = [0; 1024];
-
-    let n = match stream.read(&mut req_buf).await {
-        Ok(n) => n,
-        Err(e) => {
-            let msg = format!("failed to read from socket: {}", e);
-            return Err(Supernova::boom(&msg));
+    let req_bytes = match time::timeout(conf.request_timeout(), read_request(stream)).await {
+        Ok(v) => v?,
+        Err(_) => {
+            let msg = format!(
+                "timed out after {}s waiting for request",
+                conf.request_timeout().as_secs()
+            );
+            return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
         }
     };
 
-    let req_str = match str::from_utf8(&req_buf[..n - 1]) {
+    let req_str = match str::from_utf8(&req_bytes) {
         Ok(v) => v,
         Err(e) => {
             let msg = format!("failed to parse request as UTF-8 string: {}", e);
@@ -65,11 +154,6 @@ pub async fn entrance(
 
     log::info!("REQ {} :: {}", remote_address, req_str);
 
-    if req_str.contains("../") || req_str.contains("/..") {
-        let msg = format!("directory traversal attempted: {}", req_str);
-        return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
-    };
-
     let url = match Url::parse(req_str) {
         Ok(v) => v,
         Err(e) => {
@@ -84,6 +168,33 @@ pub async fn entrance(
         return Err(Supernova::boom(&msg).with_code(response::Code::ProxyRequestRefused));
     }
 
+    let hostname = match url.host_str() {
+        Some(v) => v,
+        None => {
+            let msg = format!("request URL has no host: {}", url);
+            return Err(Supernova::boom(&msg).with_code(response::Code::BadRequest));
+        }
+    };
+
+    if conf.host(hostname).is_none() {
+        log::warn!("REFUSED {} :: unknown host {}", remote_address, hostname);
+        let msg = format!("refusing to proxy to unknown host: {}", hostname);
+        let meta = format!("{} is not served here", hostname);
+        return Err(Supernova::boom(&msg)
+            .with_code(response::Code::ProxyRequestRefused)
+            .with_meta(&meta));
+    }
+
+    let port = url.port().unwrap_or(conf::GEMINI_PORT);
+    if !conf.serves_port(port) {
+        log::warn!("REFUSED {} :: unknown port {}", remote_address, port);
+        let msg = format!("refusing to proxy to unknown port: {}", port);
+        let meta = format!("port {} is not served here", port);
+        return Err(Supernova::boom(&msg)
+            .with_code(response::Code::ProxyRequestRefused)
+            .with_meta(&meta));
+    }
+
     Ok(url)
 }
 
@@ -94,31 +205,289 @@ pub async fn route(
     req_url: Url,
 ) -> Result<(), Supernova> {
     let path = req_url.path();
-    let root_directory = conf.root_directory();
-    let root_directory_str = root_directory.display();
+    let host = match req_url.host_str().and_then(|h| conf.host(h)) {
+        Some(v) => v,
+        None => {
+            let msg = format!("no host configured for {}", req_url);
+            return Err(Supernova::boom(&msg).with_code(response::Code::ProxyRequestRefused));
+        }
+    };
+    let req_path = file::normalise_path(path)?;
+    let peer_certs = stream.get_ref().1.peer_certificates();
+
+    conf.rate_limiter().check(
+        remote_address.ip(),
+        peer_certs.and_then(|c| c.first()),
+        &req_path,
+    )?;
+
+    let mut client_cn = None;
+    if let Some(route) = routes::find(host.client_certificates(), &req_path) {
+        client_cn = clientcert::check(route, peer_certs)?;
+        if let Some(cn) = &client_cn {
+            log::info!("REQ {} :: client certificate CN={}", remote_address, cn);
+        }
+    }
+
+    if let Some(header) = redirect::response(host.redirects(), &req_url, &req_path) {
+        log::debug!("REQ {} :: redirecting", remote_address);
+        return relay(stream, remote_address, &header).await;
+    }
+
+    gone::check(host.gone(), &req_path)?;
+
+    let mut input = None;
+    if let Some(route) = input::find_route(host.input(), &req_path) {
+        let value = match input::decode(req_url.query())? {
+            Some(v) => v,
+            None => {
+                log::debug!("REQ {} :: prompting for input", remote_address);
+                return relay(stream, remote_address, &route.prompt_header()).await;
+            }
+        };
 
-    let fixed_path = if path.is_empty() {
-        format!("{}/{}", root_directory_str, conf.index_file_name())
+        if let Some(template) = route.template() {
+            let body = input::render(host.root_directory(), template, &value).await?;
+            return send(
+                stream,
+                remote_address,
+                response::GEMINI_MIME,
+                &mut body.as_bytes(),
+            )
+            .await;
+        }
+        input = Some((route, value));
+    }
+    let input_value = input.as_ref().map(|(_, value)| value.as_str());
+
+    if let Some(route) = routes::find(host.proxy(), &req_path) {
+        log::debug!(
+            "REQ {} :: proxying to backend for {}",
+            remote_address,
+            route.path()
+        );
+        let (header, mut body) = proxy::request(route, &req_url).await?;
+        return relay_stream(stream, remote_address, &header, &mut body, route.timeout()).await;
+    }
+
+    if let Some(route) = routes::find(host.scgi(), &req_path) {
+        log::debug!(
+            "REQ {} :: forwarding to SCGI route {}",
+            remote_address,
+            route.path()
+        );
+        let path_info = &req_path[route.path().len()..];
+        let env = cgi::environment(
+            &req_url,
+            remote_address,
+            stream.get_ref().1,
+            route.path(),
+            path_info,
+            input_value,
+            client_cn.as_deref(),
+        );
+        let (header, mut body) = scgi::request(route, env).await?;
+        return relay_stream(stream, remote_address, &header, &mut body, route.timeout()).await;
+    }
+
+    // user directories get their own root, so the confinement checks keep
+    // each user inside their own directory
+    let (root, url_prefix, root_path) = match userdir::split_user_path(&req_path) {
+        Some((user, rest)) if host.user_directories().enabled() => (
+            host.user_directories().root(user).await?,
+            &req_path[..req_path.len() - rest.len()],
+            rest,
+        ),
+        _ => (host.root_directory().to_path_buf(), "", req_path.as_str()),
+    };
+
+    // scripts run as the laika user, so user directories only get them
+    // when that's asked for explicitly
+    let cgi_allowed = url_prefix.is_empty() || host.user_directories().cgi();
+    let script = if cgi_allowed {
+        cgi::find_script(host.cgi(), &root, url_prefix, root_path).await?
     } else {
-        format!("{}{}", root_directory_str, path)
+        None
     };
+    if let Some(script) = script {
+        log::debug!(
+            "REQ {} :: running CGI script {}",
+            remote_address,
+            script.path().display()
+        );
+        let env = cgi::environment(
+            &req_url,
+            remote_address,
+            stream.get_ref().1,
+            script.script_name(),
+            script.path_info(),
+            input_value,
+            client_cn.as_deref(),
+        );
+        let (header, mut body) = cgi::start(host.cgi(), &script, env).await?;
+        let idle = host.cgi().timeout();
+        return relay_stream(stream, remote_address, &header, &mut body, idle).await;
+    }
+
+    if let Some((route, value)) = &input {
+        log::debug!("REQ {} :: echoing input", remote_address);
+        let body = input::echo(route, value);
+        return send(
+            stream,
+            remote_address,
+            response::GEMINI_MIME,
+            &mut body.as_bytes(),
+        )
+        .await;
+    }
+
+    let local_path = file::local_path(&root, root_path);
+
+    let redirect = if host.directory_redirect() {
+        file::slash_redirect(&req_url, &root, &local_path).await
+    } else {
+        None
+    };
+    if let Some(url) = redirect {
+        log::debug!("REQ {} :: redirecting to {}", remote_address, url);
+        let header = response::Code::RedirectPermanent.get_header(url.as_str());
+        return relay(stream, remote_address, &header).await;
+    }
 
     log::debug!(
         "REQ {} :: full local request path: {}",
         remote_address,
-        fixed_path
+        local_path.display()
     );
 
-    let (mut fd, mime) = file::get(&fixed_path).await?;
+    if gone::is_tombstone(root_path) {
+        let msg = format!("refusing to serve tombstone {}", local_path.display());
+        return Err(Supernova::boom(&msg).with_code(response::Code::NotFound));
+    }
+
+    let resource = match file::get(&root, &local_path, host.index_file_name()).await {
+        Ok(v) => v,
+        Err(e) if e.code() == response::Code::NotFound => {
+            return match gone::tombstone(&root, &local_path, &req_path).await {
+                Some(gone) => Err(gone),
+                None => Err(e),
+            };
+        }
+        Err(e) => return Err(e),
+    };
+
+    match resource {
+        file::Resource::File { mut fd, mime } => {
+            log::debug!(
+                "REQ {} :: file {} has mime {}",
+                remote_address,
+                local_path.display(),
+                mime
+            );
+            send(stream, remote_address, &mime, &mut fd).await
+        }
+        file::Resource::Directory(dir) => {
+            if !host.listing().enabled_for(&req_path) {
+                let msg = format!("no index file in {}", dir.display());
+                return Err(Supernova::boom(&msg).with_code(response::Code::NotFound));
+            }
 
-    let header = response::Code::Success.get_header(&mime);
+            log::debug!(
+                "REQ {} :: generating listing for {}",
+                remote_address,
+                dir.display()
+            );
+            let body = listing::generate(&root, &dir, path, host.listing()).await?;
+            send(
+                stream,
+                remote_address,
+                response::GEMINI_MIME,
+                &mut body.as_bytes(),
+            )
+            .await
+        }
+    }
+}
 
-    log::debug!(
-        "REQ {} :: file {} has mime {}",
-        remote_address,
-        fixed_path,
-        mime
-    );
+// Writes a complete response produced elsewhere, header and all.
+async fn relay(
+    stream: &mut TlsStream<TcpStream>,
+    remote_address: SocketAddr,
+    response: &[u8],
+) -> Result<(), Supernova> {
+    if let Err(e) = stream.write_all(response).await {
+        let msg = format!("could not write response to tls socket: {}", e);
+        return Err(Supernova::boom(&msg));
+    }
+
+    log::info!("REQ {} :: {} bytes written", remote_address, response.len());
+
+    Ok(())
+}
+
+// Writes a response header produced elsewhere, then streams the body after
+// it unchanged. A body that stalls for `idle`, or a client that stops
+// reading for as long, ends the response.
+async fn relay_stream<R>(
+    stream: &mut TlsStream<TcpStream>,
+    remote_address: SocketAddr,
+    header: &[u8],
+    body: &mut R,
+    idle: Duration,
+) -> Result<(), Supernova>
+where
+    R: AsyncRead + Unpin,
+{
+    if let Err(e) = stream.write_all(header).await {
+        let msg = format!("could not write header to tls socket: {}", e);
+        return Err(Supernova::boom(&msg));
+    }
+
+    let mut n = header.len();
+    let mut buf = vec![0; 8192];
+    loop {
+        let read = match time::timeout(idle, body.read(&mut buf)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(v)) => v,
+            Ok(Err(e)) => {
+                let msg = format!("could not relay body to tls socket: {}", e);
+                return Err(Supernova::boom(&msg));
+            }
+            Err(_) => {
+                let msg = format!("body stalled for {}s, giving up", idle.as_secs());
+                return Err(Supernova::boom(&msg));
+            }
+        };
+        match time::timeout(idle, stream.write_all(&buf[..read])).await {
+            Ok(Ok(())) => n += read,
+            Ok(Err(e)) => {
+                let msg = format!("could not relay body to tls socket: {}", e);
+                return Err(Supernova::boom(&msg));
+            }
+            Err(_) => {
+                let msg = format!("client stopped reading for {}s, giving up", idle.as_secs());
+                return Err(Supernova::boom(&msg));
+            }
+        }
+    }
+
+    log::info!("REQ {} :: {} bytes written", remote_address, n);
+
+    Ok(())
+}
+
+// Writes a success header with the given mime type, then the body and
+// the footer.
+async fn send<R>(
+    stream: &mut TlsStream<TcpStream>,
+    remote_address: SocketAddr,
+    mime: &str,
+    body: &mut R,
+) -> Result<(), Supernova>
+where
+    R: AsyncRead + Unpin,
+{
+    let header = response::Code::Success.get_header(mime);
 
     let n = match stream.write(&header).await {
         Ok(n) => n,
@@ -128,7 +497,7 @@ pub async fn route(
         }
     };
 
-    let n = match tokio::io::copy(&mut fd, stream).await {
+    let n = match tokio::io::copy(body, stream).await {
         Ok(v) => v as usize + n,
         Err(e) => {
             let msg = format!("could not write body to tls socket: {}", e);
@@ -148,3 +517,67 @@ pub async fn route(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_request_single_read() {
+        let mut input: &[u8] = b"gemini://example.com/\r\n";
+        let req = read_request(&mut input).await.unwrap();
+        assert_eq!(req, b"gemini://example.com/");
+    }
+
+    #[tokio::test]
+    async fn read_request_split_reads() {
+        let mut input = split_reader(&[b"gemini://exa", b"mple.com/\r", b"\n"]);
+        let req = read_request(&mut input).await.unwrap();
+        assert_eq!(req, b"gemini://example.com/");
+    }
+
+    #[tokio::test]
+    async fn read_request_missing_crlf() {
+        let mut input: &[u8] = b"gemini://example.com/";
+        let err = read_request(&mut input).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+
+        let mut input: &[u8] = b"gemini://example.com/\n";
+        let err = read_request(&mut input).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+
+        let mut input: &[u8] = b"";
+        let err = read_request(&mut input).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+    }
+
+    #[tokio::test]
+    async fn read_request_size_limit() {
+        let mut exact = vec![b'a'; MAX_REQUEST_BYTES];
+        exact.extend_from_slice(b"\r\n");
+        let req = read_request(&mut exact.as_slice()).await.unwrap();
+        assert_eq!(req.len(), MAX_REQUEST_BYTES);
+
+        let mut over = vec![b'a'; MAX_REQUEST_BYTES + 1];
+        over.extend_from_slice(b"\r\n");
+        let err = read_request(&mut over.as_slice()).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+
+        let endless = vec![b'a'; MAX_REQUEST_BYTES * 4];
+        let err = read_request(&mut endless.as_slice()).await.unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+    }
+
+    // Hands out each slice in its own read() call, like separate TLS records.
+    fn split_reader(parts: &[&[u8]]) -> impl AsyncRead + Unpin {
+        let (client, mut server) = tokio::io::duplex(64);
+        let parts: Vec<Vec<u8>> = parts.iter().map(|p| p.to_vec()).collect();
+        tokio::spawn(async move {
+            for p in parts {
+                server.write_all(&p).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        client
+    }
+}
@@ -0,0 +1,202 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::path::PathBuf;
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::err::Supernova;
+use crate::file;
+use crate::response;
+
+// Replaced with the user's input in templates.
+const PLACEHOLDER: &str = "{input}";
+
+/// A request path that asks the client for input before anything is served.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputRoute {
+    path: String,
+    prompt: String,
+    #[serde(default)]
+    sensitive: bool,
+    // gemtext file, relative to the content root, served with {input}
+    // replaced by the query. without one the request is routed as usual,
+    // and answered by echo() when nothing else handles it.
+    template: Option<PathBuf>,
+}
+
+impl InputRoute {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if !self.path.starts_with('/') {
+            let msg = format!("input path must start with /: {}", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+        // two digits, a space and the prompt have to fit in a 1024 byte meta
+        if self.prompt.is_empty() || self.prompt.len() > 1024 || self.prompt.contains(['\r', '\n'])
+        {
+            let msg = format!(
+                "input prompt for {} must be one line of 1 to 1024 bytes",
+                self.path
+            );
+            return Err(Supernova::boom(&msg));
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+
+    pub fn template(&self) -> Option<&PathBuf> {
+        self.template.as_ref()
+    }
+
+    /// The 10 or 11 response asking for input.
+    pub fn prompt_header(&self) -> Vec<u8> {
+        let code = if self.sensitive {
+            response::Code::SensitiveInput
+        } else {
+            response::Code::Input
+        };
+
+        code.get_header(&self.prompt)
+    }
+}
+
+/// Finds the route for exactly `req_path`. Unlike most routes, an input
+/// route doesn't cover the paths below it.
+pub fn find_route<'a>(routes: &'a [InputRoute], req_path: &str) -> Option<&'a InputRoute> {
+    let req_path = req_path.trim_end_matches('/');
+    routes.iter().find(|r| r.path() == req_path)
+}
+
+/// Decodes the query string holding the user's input. An empty query means
+/// there's no input yet.
+pub fn decode(query: Option<&str>) -> Result<Option<String>, Supernova> {
+    let query = match query {
+        Some(q) if !q.is_empty() => q,
+        _ => return Ok(None),
+    };
+
+    match percent_decode_str(query).decode_utf8() {
+        Ok(v) => Ok(Some(v.into_owned())),
+        Err(e) => {
+            let msg = format!("input is not valid UTF-8: {}", e);
+            Err(Supernova::boom(&msg).with_code(response::Code::BadRequest))
+        }
+    }
+}
+
+/// Fills in a template from the content root with the user's input.
+pub async fn render(
+    root: &std::path::Path,
+    template: &std::path::Path,
+    input: &str,
+) -> Result<String, Supernova> {
+    let req_path = file::normalise_path(&template.to_string_lossy())?;
+    let path = file::confine(root, &file::local_path(root, &req_path)).await?;
+    let body = fs::read_to_string(&path).await.map_err(file::wrap_io_err)?;
+
+    // a line break in the input could start a link or heading line
+    let input = input.replace(['\r', '\n'], " ");
+
+    Ok(body.replace(PLACEHOLDER, &input))
+}
+
+/// The built-in answer for an input route with nothing behind it: the
+/// prompt as a heading and the input quoted below it.
+pub fn echo(route: &InputRoute, input: &str) -> String {
+    // quoted, so the input can't turn into a link or heading line
+    let input = input.replace(['\r', '\n'], " ");
+
+    format!("# {}\n\n> {}\n", route.prompt, input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, prompt: &str, sensitive: bool) -> InputRoute {
+        InputRoute {
+            path: path.to_string(),
+            prompt: prompt.to_string(),
+            sensitive,
+            template: None,
+        }
+    }
+
+    #[test]
+    fn prompts() {
+        let routes = vec![
+            route("/search/", "Search terms", false),
+            route("/login", "Password", true),
+        ];
+        let search = find_route(&routes, "/search").unwrap();
+        assert_eq!(search.prompt_header(), b"10 Search terms\r\n");
+        let login = find_route(&routes, "/login/").unwrap();
+        assert_eq!(login.prompt_header(), b"11 Password\r\n");
+        assert!(find_route(&routes, "/search/more").is_none());
+
+        assert!(route("/x", "", false).validate().is_err());
+        assert!(route("/x", "two\nlines", false).validate().is_err());
+        assert!(route("x", "ok", false).validate().is_err());
+    }
+
+    #[test]
+    fn echoing() {
+        let search = route("/search", "Search terms", false);
+        assert_eq!(
+            echo(&search, "cats\n=> evil"),
+            "# Search terms\n\n> cats => evil\n"
+        );
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode(None).unwrap(), None);
+        assert_eq!(decode(Some("")).unwrap(), None);
+        assert_eq!(
+            decode(Some("caf%C3%A9%20au+lait")).unwrap().unwrap(),
+            "café au+lait"
+        );
+        assert_eq!(
+            decode(Some("%FF")).unwrap_err().code(),
+            response::Code::BadRequest
+        );
+    }
+
+    #[tokio::test]
+    async fn templates() {
+        let root = std::env::temp_dir().join(format!("laika-input-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("hello.gmi"), "# Hello, {input}!\n").unwrap();
+
+        let body = render(&root, std::path::Path::new("hello.gmi"), "you\n=> evil")
+            .await
+            .unwrap();
+        assert_eq!(body, "# Hello, you => evil!\n");
+
+        let err = render(&root, std::path::Path::new("../etc/passwd"), "x")
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), response::Code::BadRequest);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,359 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::err::Supernova;
+use crate::response;
+
+/// Caps on how much work laika takes on at once. 0 means no cap.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConnLimitConf {
+    max_connections: usize,
+    max_handshakes: usize,
+    max_connections_per_ip: usize,
+    // connections over the other caps being answered with 41 at once
+    max_refusals: usize,
+}
+
+impl Default for ConnLimitConf {
+    fn default() -> Self {
+        ConnLimitConf {
+            max_connections: 1024,
+            max_handshakes: 256,
+            max_connections_per_ip: 32,
+            max_refusals: 32,
+        }
+    }
+}
+
+/// Keeps count of the connections and TLS handshakes in progress.
+#[derive(Debug, Clone)]
+pub struct ConnLimits {
+    max_connections_per_ip: usize,
+    connections: Option<Cap>,
+    handshakes: Option<Cap>,
+    refusals: Option<Cap>,
+    // only addresses with connections open are in here
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// A semaphore that can be resized while its permits are out.
+#[derive(Debug, Clone)]
+struct Cap {
+    permits: usize,
+    semaphore: Arc<Semaphore>,
+    // permits to take out of circulation as they come back, after the cap
+    // was lowered below the number in use
+    owed: Arc<AtomicUsize>,
+}
+
+impl Cap {
+    fn new(permits: usize) -> Option<Cap> {
+        if permits == 0 {
+            return None;
+        }
+        let permits = permits.min(Semaphore::MAX_PERMITS);
+        Some(Cap {
+            permits,
+            semaphore: Arc::new(Semaphore::new(permits)),
+            owed: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    // The same semaphore with `permits` in all. Going to or from no cap
+    // starts over, since uncapped connections hold no permits.
+    fn resize(old: &Option<Cap>, permits: usize) -> Option<Cap> {
+        let old = match old {
+            Some(v) if permits != 0 => v,
+            _ => return Cap::new(permits),
+        };
+        let permits = permits.min(Semaphore::MAX_PERMITS);
+
+        if permits >= old.permits {
+            // permits still owed from lowering it before are just kept
+            let more = permits - old.permits;
+            let mut cancelled = 0;
+            let _ = old
+                .owed
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |owed| {
+                    cancelled = owed.min(more);
+                    Some(owed - cancelled)
+                });
+            old.semaphore.add_permits(more - cancelled);
+        } else {
+            let mut fewer = old.permits - permits;
+            let free = old.semaphore.available_permits().min(fewer);
+            if let Ok(v) = old.semaphore.try_acquire_many(free as u32) {
+                v.forget();
+                fewer -= free;
+            }
+            old.owed.fetch_add(fewer, Ordering::SeqCst);
+        }
+
+        Some(Cap {
+            permits,
+            semaphore: old.semaphore.clone(),
+            owed: old.owed.clone(),
+        })
+    }
+
+    fn try_acquire(&self) -> Option<CapPermit> {
+        let permit = self.semaphore.clone().try_acquire_owned().ok()?;
+        Some(CapPermit {
+            permit: Some(permit),
+            owed: self.owed.clone(),
+        })
+    }
+}
+
+/// One of a capped number of slots, given back when dropped.
+pub struct CapPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    owed: Arc<AtomicUsize>,
+}
+
+impl Drop for CapPermit {
+    fn drop(&mut self) {
+        let owing = self
+            .owed
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+        if let (Ok(_), Some(permit)) = (owing, self.permit.take()) {
+            permit.forget();
+        }
+    }
+}
+
+/// Held for as long as a connection is being served.
+pub struct ConnPermit {
+    _connection: Option<CapPermit>,
+    _ip: IpPermit,
+}
+
+struct IpPermit {
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut per_ip = match self.per_ip.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+        if let Some(n) = per_ip.get_mut(&self.ip) {
+            *n -= 1;
+            if *n == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ConnLimits {
+    pub fn new(conf: &ConnLimitConf) -> ConnLimits {
+        ConnLimits {
+            max_connections_per_ip: conf.max_connections_per_ip,
+            connections: Cap::new(conf.max_connections),
+            handshakes: Cap::new(conf.max_handshakes),
+            refusals: Cap::new(conf.max_refusals),
+            per_ip: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// These caps, counting the connections `old` has open. Used on reload,
+    /// so connections started under the old config still count.
+    pub fn carry_over(&self, old: &ConnLimits) -> ConnLimits {
+        let permits = |cap: &Option<Cap>| cap.as_ref().map_or(0, |c| c.permits);
+        ConnLimits {
+            max_connections_per_ip: self.max_connections_per_ip,
+            connections: Cap::resize(&old.connections, permits(&self.connections)),
+            handshakes: Cap::resize(&old.handshakes, permits(&self.handshakes)),
+            refusals: Cap::resize(&old.refusals, permits(&self.refusals)),
+            per_ip: old.per_ip.clone(),
+        }
+    }
+
+    /// Counts a new connection from `ip`. Connections over either cap get
+    /// a 41 to send back instead.
+    pub fn admit(&self, ip: IpAddr) -> Result<ConnPermit, Supernova> {
+        let ip = ip.to_canonical();
+        let unavailable = |why: &str| {
+            let msg = format!("refusing connection from {}: {}", ip, why);
+            Supernova::boom(&msg).with_code(response::Code::ServerUnavailable)
+        };
+
+        let connection = match &self.connections {
+            Some(c) => match c.try_acquire() {
+                Some(v) => Some(v),
+                None => return Err(unavailable("too many connections")),
+            },
+            None => None,
+        };
+
+        let mut per_ip = match self.per_ip.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+        let n = per_ip.entry(ip).or_insert(0);
+        if self.max_connections_per_ip != 0 && *n >= self.max_connections_per_ip {
+            if *n == 0 {
+                per_ip.remove(&ip);
+            }
+            return Err(unavailable("too many connections from this address"));
+        }
+        *n += 1;
+
+        Ok(ConnPermit {
+            _connection: connection,
+            _ip: IpPermit {
+                per_ip: self.per_ip.clone(),
+                ip,
+            },
+        })
+    }
+
+    /// A slot for one TLS handshake, or `None` when too many are already in
+    /// progress. Without a handshake there is no way to answer, so those
+    /// connections are just closed.
+    pub fn handshake(&self) -> Result<Option<CapPermit>, Supernova> {
+        match &self.handshakes {
+            Some(c) => match c.try_acquire() {
+                Some(v) => Ok(Some(v)),
+                None => Err(Supernova::boom("too many TLS handshakes in progress")),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// A slot for answering a connection `admit` refused, or an error when
+    /// too many are already being answered. Those connections are closed
+    /// without an answer, so refusals can't pile up on their own.
+    pub fn refusal(&self) -> Result<Option<CapPermit>, Supernova> {
+        match &self.refusals {
+            Some(c) => match c.try_acquire() {
+                Some(v) => Ok(Some(v)),
+                None => Err(Supernova::boom(
+                    "too many refused connections being answered",
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(connections: usize, handshakes: usize, per_ip: usize) -> ConnLimits {
+        ConnLimits::new(&ConnLimitConf {
+            max_connections: connections,
+            max_handshakes: handshakes,
+            max_connections_per_ip: per_ip,
+            max_refusals: 1,
+        })
+    }
+
+    #[test]
+    fn connection_caps() {
+        let capped = limits(3, 0, 2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = capped.admit(a).unwrap();
+        let _second = capped.admit(a).unwrap();
+        let err = capped.admit(a).err().unwrap();
+        assert_eq!(err.code(), response::Code::ServerUnavailable);
+
+        // the refusal above didn't use up a connection
+        let _third = capped.admit(b).unwrap();
+        assert!(capped.admit(b).is_err());
+
+        drop(first);
+        assert!(capped.admit(a).is_ok());
+        assert_eq!(capped.per_ip.lock().unwrap().get(&a), Some(&1));
+    }
+
+    #[test]
+    fn per_ip_cleanup() {
+        let capped = limits(0, 0, 1);
+        let a: IpAddr = "::ffff:192.0.2.1".parse().unwrap();
+
+        let permit = capped.admit(a).unwrap();
+        assert!(capped.admit("192.0.2.1".parse().unwrap()).is_err());
+        drop(permit);
+        assert!(capped.per_ip.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn handshake_cap() {
+        let capped = limits(0, 1, 0);
+        let permit = capped.handshake().unwrap();
+        assert!(permit.is_some());
+        assert!(capped.handshake().is_err());
+        drop(permit);
+        assert!(capped.handshake().is_ok());
+
+        assert!(limits(0, 0, 0).handshake().unwrap().is_none());
+    }
+
+    #[test]
+    fn refusal_cap() {
+        let capped = limits(1, 0, 0);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let _served = capped.admit(a).unwrap();
+        assert!(capped.admit(a).is_err());
+        let answering = capped.refusal().unwrap();
+        assert!(answering.is_some());
+        assert!(capped.refusal().is_err());
+        drop(answering);
+        assert!(capped.refusal().is_ok());
+    }
+
+    #[test]
+    fn carry_over() {
+        let old = limits(3, 0, 2);
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = old.admit(a).unwrap();
+        let second = old.admit("192.0.2.2".parse().unwrap()).unwrap();
+
+        // lowered below what's in use: nothing new until enough close
+        let lowered = limits(1, 0, 1).carry_over(&old);
+        assert!(lowered.admit("192.0.2.3".parse().unwrap()).is_err());
+        drop(first);
+        assert!(lowered.admit("192.0.2.3".parse().unwrap()).is_err());
+        drop(second);
+        let third = lowered.admit(a).unwrap();
+        assert!(lowered.admit(a).is_err());
+
+        // raised again, and the per-address count came along
+        let raised = limits(2, 0, 1).carry_over(&lowered);
+        assert!(raised.admit(a).is_err());
+        let _fourth = raised.admit("192.0.2.2".parse().unwrap()).unwrap();
+        assert!(raised.admit("192.0.2.3".parse().unwrap()).is_err());
+        drop(third);
+        assert!(raised.admit(a).is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::err::Supernova;
+
+/// Socket settings for the listening addresses and the connections they
+/// accept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ListenConf {
+    // whether IPv6 listeners keep off IPv4. unset leaves the system
+    // default (net.ipv6.bindv6only on Linux, usually off)
+    v6_only: Option<bool>,
+    // connections the kernel queues before they're accepted
+    backlog: u32,
+    nodelay: bool,
+    // seconds a connection sits idle before keepalive probes start; 0
+    // turns keepalive off
+    keepalive: u64,
+}
+
+impl Default for ListenConf {
+    fn default() -> Self {
+        ListenConf {
+            v6_only: None,
+            backlog: 1024,
+            nodelay: true,
+            keepalive: 0,
+        }
+    }
+}
+
+impl ListenConf {
+    /// Opens a listening socket on `addr`, which may name a host instead of
+    /// an IP address. Only its first address is used.
+    pub fn bind(&self, addr: &str) -> Result<TcpListener, Supernova> {
+        let sock_addr = match resolve(addr) {
+            Some(v) => v,
+            None => {
+                let msg = format!("Could not resolve bind address {}", addr);
+                return Err(Supernova::boom(&msg));
+            }
+        };
+
+        self.socket(sock_addr).map_err(|e| {
+            let msg = format!("Could not bind to {}: {}", addr, e);
+            Supernova::boom(&msg)
+        })
+    }
+
+    fn socket(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let (true, Some(v6_only)) = (addr.is_ipv6(), self.v6_only) {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.min(i32::MAX as u32) as i32)?;
+
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Whether going from `old` to these settings needs the listening
+    /// sockets opened again. The rest apply to each new connection.
+    pub fn rebinds(&self, old: &ListenConf) -> bool {
+        self.v6_only != old.v6_only || self.backlog != old.backlog
+    }
+
+    /// Applies the per-connection settings to an accepted stream.
+    pub fn tune(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(stream);
+        if self.keepalive == 0 {
+            socket.set_keepalive(false)
+        } else {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(self.keepalive));
+            socket.set_tcp_keepalive(&keepalive)
+        }
+    }
+}
+
+fn resolve(addr: &str) -> Option<SocketAddr> {
+    addr.to_socket_addrs().ok()?.next()
+}
+
+/// The port of an address:port string, if it has one.
+pub fn port(addr: &str) -> Option<u16> {
+    addr.rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dual_stack() {
+        let listen = ListenConf {
+            v6_only: Some(true),
+            ..Default::default()
+        };
+        let v4 = listen.bind("127.0.0.1:0").unwrap();
+        let port = v4.local_addr().unwrap().port();
+
+        // with v6_only the IPv6 wildcard leaves the IPv4 port alone
+        let v6 = match listen.bind(&format!("[::]:{}", port)) {
+            Ok(v) => v,
+            // no IPv6 here
+            Err(_) => return,
+        };
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+
+        let client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (stream, _) = v4.accept().await.unwrap();
+        listen.tune(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        drop(client);
+
+        assert!(listen.bind("no such host:1965").is_err());
+        assert_eq!(super::port("[::]:1965"), Some(1965));
+        assert_eq!(super::port("localhost"), None);
+    }
+}
@@ -0,0 +1,306 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::err::Supernova;
+use crate::file;
+use crate::gone;
+
+/// Settings for the gemtext listings generated for directories that have
+/// no index file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ListingConf {
+    enabled: bool,
+    show_size: bool,
+    show_mtime: bool,
+    sort: SortOrder,
+    // request path prefix -> whether listings are generated below it
+    directories: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Name,
+    Size,
+    Mtime,
+}
+
+impl ListingConf {
+    /// Whether a listing may be generated for `req_path`. The longest
+    /// matching entry in `directories` wins over the host-wide setting.
+    pub fn enabled_for(&self, req_path: &str) -> bool {
+        self.directories
+            .iter()
+            .filter(|(prefix, _)| file::path_under(req_path, prefix))
+            .max_by_key(|(prefix, _)| prefix.trim_end_matches('/').len())
+            .map(|(_, &enabled)| enabled)
+            .unwrap_or(self.enabled)
+    }
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    mtime: SystemTime,
+}
+
+// Replaces control characters, so a newline in a file or directory name
+// can't start a gemtext line of its own.
+fn displayable(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_control() { '\u{FFFD}' } else { c })
+        .collect()
+}
+
+// The decoded path for the listing's heading.
+fn heading(base: &str) -> String {
+    displayable(&percent_decode_str(base).decode_utf8_lossy())
+}
+
+/// Builds a gemtext listing of `dir`. `url_path` is the (still encoded)
+/// path the client asked for and is used as the base of every link.
+/// Hidden entries, and anything that can't be read or that resolves
+/// outside of `root`, are left out.
+pub async fn generate(
+    root: &Path,
+    dir: &Path,
+    url_path: &str,
+    conf: &ListingConf,
+) -> Result<String, Supernova> {
+    let mut read_dir = fs::read_dir(dir).await.map_err(file::wrap_io_err)?;
+    let mut entries = Vec::new();
+
+    loop {
+        let dir_entry = match read_dir.next_entry().await {
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            Err(e) => {
+                log::debug!("skipping unreadable entry in {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+
+        let name = match dir_entry.file_name().into_string() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if name.starts_with('.') || gone::is_tombstone(&name) {
+            continue;
+        }
+
+        if let Some(entry) = readable_entry(root, &dir_entry.path(), name).await {
+            entries.push(entry);
+        }
+    }
+
+    sort_entries(&mut entries, conf.sort);
+
+    let base = format!("{}/", url_path.trim_end_matches('/'));
+    let mut body = format!("# Index of {}\n\n", heading(&base));
+
+    if base != "/" {
+        let parent = match base.trim_end_matches('/').rsplit_once('/') {
+            Some((parent, _)) => format!("{}/", parent),
+            None => String::from("/"),
+        };
+        body.push_str(&format!("=> {} ..\n", parent));
+    }
+
+    for entry in &entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        let mut details = Vec::new();
+        if conf.show_size && !entry.is_dir {
+            details.push(human_size(entry.size));
+        }
+        if conf.show_mtime {
+            details.push(format_mtime(entry.mtime));
+        }
+
+        let link = format!("{}{}{}", base, file::encode_segment(&entry.name), slash);
+        let name = displayable(&entry.name);
+        if details.is_empty() {
+            body.push_str(&format!("=> {} {}{}\n", link, name, slash));
+        } else {
+            body.push_str(&format!(
+                "=> {} {}{} ({})\n",
+                link,
+                name,
+                slash,
+                details.join(", ")
+            ));
+        }
+    }
+
+    Ok(body)
+}
+
+async fn readable_entry(root: &Path, path: &Path, name: String) -> Option<Entry> {
+    let path = file::confine(root, path).await.ok()?;
+    let metadata = fs::metadata(&path).await.ok()?;
+
+    let readable = if metadata.is_dir() {
+        fs::read_dir(&path).await.is_ok()
+    } else {
+        fs::File::open(&path).await.is_ok()
+    };
+    if !readable {
+        return None;
+    }
+
+    Some(Entry {
+        name,
+        is_dir: metadata.is_dir(),
+        size: metadata.len(),
+        mtime: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+    })
+}
+
+// Directories come first. Names sort ascending, sizes and times descending.
+fn sort_entries(entries: &mut [Entry], order: SortOrder) {
+    entries.sort_by(|a, b| {
+        b.is_dir.cmp(&a.is_dir).then_with(|| match order {
+            SortOrder::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            SortOrder::Size => b.size.cmp(&a.size),
+            SortOrder::Mtime => b.mtime.cmp(&a.mtime),
+        })
+    });
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn format_mtime(mtime: SystemTime) -> String {
+    let dt = time::OffsetDateTime::from(mtime);
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}",
+        dt.year(),
+        dt.month() as u8,
+        dt.day(),
+        dt.hour(),
+        dt.minute()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_directory_settings() {
+        let mut conf = ListingConf::default();
+        assert!(!conf.enabled_for("/pub"));
+
+        conf.directories.insert(String::from("/pub/"), true);
+        conf.directories.insert(String::from("/pub/private"), false);
+        assert!(conf.enabled_for("/pub"));
+        assert!(conf.enabled_for("/pub/files"));
+        assert!(!conf.enabled_for("/pub/private/x"));
+        assert!(!conf.enabled_for("/public"));
+
+        conf.enabled = true;
+        assert!(conf.enabled_for("/public"));
+        assert!(!conf.enabled_for("/pub/private"));
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[tokio::test]
+    async fn listing() {
+        let root = std::env::temp_dir().join(format!("laika-listing-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub dir")).unwrap();
+        std::fs::write(root.join("b.gmi"), "bb").unwrap();
+        std::fs::write(root.join("café notes.gmi"), "c").unwrap();
+        std::fs::write(root.join(".hidden"), "h").unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        let conf = ListingConf::default();
+        let body = generate(&root, &root, "/files", &conf).await.unwrap();
+        assert_eq!(
+            body,
+            "# Index of /files/\n\n\
+             => / ..\n\
+             => /files/sub%20dir/ sub dir/\n\
+             => /files/b.gmi b.gmi\n\
+             => /files/caf%C3%A9%20notes.gmi café notes.gmi\n"
+        );
+
+        let conf = ListingConf {
+            show_size: true,
+            sort: SortOrder::Size,
+            ..Default::default()
+        };
+        let body = generate(&root, &root, "/", &conf).await.unwrap();
+        assert_eq!(
+            body,
+            "# Index of /\n\n\
+             => /sub%20dir/ sub dir/\n\
+             => /b.gmi b.gmi (2 B)\n\
+             => /caf%C3%A9%20notes.gmi café notes.gmi (1 B)\n"
+        );
+
+        let body = generate(&root, &root.join("sub dir"), "/caf%C3%A9/sub%20dir", &conf)
+            .await
+            .unwrap();
+        assert!(body.starts_with("# Index of /café/sub dir/\n\n=> /caf%C3%A9/ ..\n"));
+        assert_eq!(heading("/a%0A%23%20b/"), "/a\u{FFFD}# b/");
+
+        // a name can't add lines of its own to the listing
+        std::fs::create_dir_all(root.join("odd")).unwrap();
+        std::fs::write(root.join("odd").join("x\n=> gemini:evil click"), "x").unwrap();
+        let body = generate(&root, &root.join("odd"), "/odd", &ListingConf::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "# Index of /odd/\n\n\
+             => / ..\n\
+             => /odd/x%0A=%3E%20gemini:evil%20click x\u{FFFD}=> gemini:evil click\n"
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -16,7 +16,7 @@
 
 use std::error::Error;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 
 use simplelog::*;
 
@@ -24,8 +24,8 @@ use crate::conf;
 
 pub fn init(conf: &conf::Conf) -> Result<(), Box<dyn Error>> {
     let log_fd = fs::OpenOptions::new()
-        .write(true)
         .create(true)
+        .append(true)
         .open(conf.log_file())?;
 
     let log_level = if conf.debug() {
@@ -34,7 +34,7 @@ pub fn init(conf: &conf::Conf) -> Result<(), Box<dyn Error>> {
         LevelFilter::Info
     };
 
-    if conf.log_file() == PathBuf::from("stderr") {
+    if conf.log_file() == Path::new("stderr") {
         TermLogger::init(
             log_level,
             Config::default(),
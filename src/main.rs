@@ -14,23 +14,55 @@
  * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
  */
 
+use std::net::SocketAddr;
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use tokio_rustls::server::TlsStream;
 
+mod certgen;
+mod cgi;
+mod clientcert;
 mod conf;
 mod err;
 mod file;
+mod gone;
 mod handlers;
+mod input;
+mod limits;
+mod listen;
+mod listing;
 mod logging;
+mod proxy;
+mod ratelimit;
+mod redirect;
 mod response;
+mod routes;
+mod scgi;
+mod tls;
+mod userdir;
 
 static LAIKA_VERSION: &str = "0.1";
 
 #[tokio::main]
 async fn main() {
-    let conf = match conf::Conf::new() {
-        Ok(v) => v,
+    if let Some(conf::Command::GenCert(args)) = conf::command() {
+        if let Err(e) = certgen::run(&args) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut conf = match conf::Conf::new() {
+        Ok(v) => Arc::new(v),
         Err(e) => {
             eprintln!("{}", e);
             process::exit(1);
@@ -43,71 +75,315 @@ async fn main() {
     };
 
     log::info!("laika {} starting", LAIKA_VERSION);
-    log::info!("Binding to {}", conf.bind_address());
+    log::info!("Binding to {}", conf.bind_addresses().join(", "));
+    log_notices(&conf);
 
     log::debug!("laika config:\n{:?}", conf);
 
-    let (tcp_listener, tls_acceptor) = match conf.get_listener().await {
+    let (tcp_listeners, mut tls_acceptor) = match conf.get_listeners() {
         Ok((tcp, tls)) => (tcp, tls),
         Err(e) => {
-            log::error!("Could not get TCP listener or TLS acceptor: {}", e);
+            log::error!("Could not get TCP listeners or TLS acceptor: {}", e);
             process::exit(1);
         }
     };
 
+    // every address gets an accept loop of its own, all feeding this one
+    let (accepted_tx, mut accepted) = mpsc::channel(tcp_listeners.len());
+    let mut accept_loops = JoinSet::new();
+    for tcp_listener in tcp_listeners {
+        accept_loops.spawn(accept_loop(tcp_listener, accepted_tx.clone()));
+    }
+    drop(accepted_tx);
+
+    let mut sigterm = signal_stream(SignalKind::terminate());
+    let mut sigint = signal_stream(SignalKind::interrupt());
+    let mut sighup = signal_stream(SignalKind::hangup());
+    let mut connections = JoinSet::new();
+    let mut cert_watch = tls::CertWatch::new(&conf.cert_paths());
+    let mut cert_check = cert_check_timer(&conf);
+    let mut reloading: Option<(Reload, task::JoinHandle<Reloaded>)> = None;
+
     loop {
-        let (socket, remote_address) = match tcp_listener.accept().await {
+        let reload_running = reloading.is_some();
+        let (socket, remote_address) = tokio::select! {
+            Some(v) = accepted.recv() => v,
+            // reap finished connections so the set only holds live ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = sigterm.recv() => {
+                log::info!("SIGTERM received, shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                log::info!("SIGINT received, shutting down");
+                break;
+            }
+            // the files are read on a blocking thread, and connections keep
+            // being accepted under the old config until it's done. only one
+            // reload runs at a time, so neither undoes the other.
+            _ = sighup.recv() => {
+                if reload_running {
+                    log::warn!("SIGHUP received, but a reload is already in progress");
+                } else {
+                    log::info!("SIGHUP received, reloading configuration");
+                    let old = conf.clone();
+                    let handle = task::spawn_blocking(move || reload(&old));
+                    reloading = Some((Reload::Config, handle));
+                }
+                continue;
+            }
+            // connections already going keep the config they started with
+            reloaded = async { (&mut reloading.as_mut().unwrap().1).await }, if reload_running => {
+                let kind = match reloading.take() {
+                    Some((kind, _)) => kind,
+                    None => continue,
+                };
+                match (kind, reloaded) {
+                    (Reload::Config, Ok(Ok((new_conf, new_acceptor)))) => {
+                        if new_conf.cert_check_interval() != conf.cert_check_interval() {
+                            cert_check = cert_check_timer(&new_conf);
+                        }
+                        conf = new_conf;
+                        tls_acceptor = new_acceptor;
+                        cert_watch = tls::CertWatch::new(&conf.cert_paths());
+                        log_notices(&conf);
+                        log::info!("Configuration reloaded");
+                    }
+                    (Reload::Certs, Ok(Ok((new_conf, new_acceptor)))) => {
+                        conf = new_conf;
+                        tls_acceptor = new_acceptor;
+                        log::info!("Certificates reloaded");
+                    }
+                    (Reload::Config, Ok(Err(e))) => {
+                        log::error!("Could not reload configuration, keeping the old one: {}", e);
+                    }
+                    (Reload::Certs, Ok(Err(e))) => {
+                        log::error!(
+                            "Could not reload certificates, keeping the last good ones: {}",
+                            e
+                        );
+                    }
+                    (_, Err(e)) => {
+                        log::error!("Reload failed, keeping the old configuration: {}", e);
+                    }
+                }
+                continue;
+            }
+            // a change seen while another reload runs waits for the next tick
+            _ = cert_check.tick(), if !conf.cert_check_interval().is_zero() && !reload_running => {
+                if cert_watch.changed(&conf.cert_paths()) {
+                    log::info!("Certificate files changed, reloading them");
+                    let old = conf.clone();
+                    let handle = task::spawn_blocking(move || reload_certs(&old));
+                    reloading = Some((Reload::Certs, handle));
+                }
+                continue;
+            }
+        };
+        if let Err(e) = conf.listen().tune(&socket) {
+            log::error!("Could not set socket options for {}: {}", remote_address, e);
+        }
+        let handshake = match conf.conn_limits().handshake() {
             Ok(v) => v,
             Err(e) => {
-                log::error!("Could not accept connection: {}", e);
+                log::warn!("REFUSED {} :: {}", remote_address, e);
                 continue;
             }
         };
+        let admission = conf.conn_limits().admit(remote_address.ip());
+        let refusal = match &admission {
+            Ok(_) => None,
+            Err(e) => match conf.conn_limits().refusal() {
+                Ok(v) => v,
+                Err(full) => {
+                    log::warn!("REFUSED {} :: {}; {}", remote_address, e, full);
+                    continue;
+                }
+            },
+        };
         let tls_acceptor = tls_acceptor.clone();
         let conf = conf.clone();
 
-        tokio::spawn(async move {
-            let mut stream = match tls_acceptor.accept(socket).await {
-                Ok(s) => s,
-                Err(e) => {
+        connections.spawn(async move {
+            // a handshake slot shouldn't be held by a client that stalls
+            let accept = time::timeout(conf.request_timeout(), tls_acceptor.accept(socket));
+            let mut stream = match accept.await {
+                Ok(Ok(s)) => s,
+                Ok(Err(e)) => {
                     log::error!("could not negotiate TLS: {}", e);
                     return;
                 }
+                Err(_) => {
+                    log::error!("timed out negotiating TLS with {}", remote_address);
+                    return;
+                }
             };
+            drop(handshake);
+            let tls_conn = stream.get_ref().1;
+            log::debug!(
+                "{} :: negotiated {} with {}",
+                remote_address,
+                tls_conn
+                    .protocol_version()
+                    .map(|v| format!("{:?}", v).replace('_', "."))
+                    .unwrap_or_default(),
+                tls_conn
+                    .negotiated_cipher_suite()
+                    .map(|s| format!("{:?}", s.suite()))
+                    .unwrap_or_default()
+            );
 
-            log::info!("REQ {} :: Connected", remote_address);
-
-            let req_url = match handlers::entrance(&mut stream, remote_address).await {
+            let _permit = match admission {
                 Ok(v) => v,
                 Err(e) => {
-                    log::error!("REQ {} :: {}", remote_address, e);
-                    if e.code() != response::Code::Unknown {
-                        let header = e.code().get_header("");
-                        match stream.write(&header).await {
-                            Ok(_) => (),
-                            Err(e) => {
-                                log::error!("REQ {} :: {}", remote_address, e);
-                            }
-                        };
-                    }
-                    handlers::flush_and_kill(&mut stream, remote_address).await;
-                    log::info!("REQ {} :: Terminated", remote_address);
+                    log::warn!("REFUSED {} :: {}", remote_address, e);
+                    handlers::shed(&conf, &mut stream, remote_address, &e).await;
+                    drop(refusal);
                     return;
                 }
             };
 
-            if let Err(e) = handlers::route(&conf, &mut stream, remote_address, req_url).await {
-                log::error!("REQ {} :: {}", remote_address, e);
-                if e.code() != response::Code::Unknown {
-                    let header = e.code().get_header("");
-                    if let Err(e) = stream.write_all(&header).await {
-                        log::error!("REQ {} :: {}", remote_address, e);
-                    }
+            serve(&conf, &mut stream, remote_address).await;
+        });
+    }
+
+    accept_loops.shutdown().await;
+    drain(connections, conf.shutdown_grace()).await;
+    log::info!("laika {} stopped", LAIKA_VERSION);
+    log::logger().flush();
+}
+
+// What started the reload in progress.
+enum Reload {
+    Config,
+    Certs,
+}
+
+// A config and TLS settings ready to swap in.
+type Reloaded = Result<(Arc<conf::Conf>, tls::Acceptor), err::Supernova>;
+
+// Reads the config again and builds its TLS settings, so nothing is swapped
+// in unless both work. The listening sockets and the logger stay as they
+// are, so changes to bind_address, listen's v6_only and backlog, log_file
+// and debug need a restart. Rate limits and connection caps keep counting
+// what they counted before.
+fn reload(old: &conf::Conf) -> Reloaded {
+    let mut conf = conf::Conf::new()?;
+    conf.carry_over_limits(old);
+    let tls_acceptor = conf.tls_acceptor()?;
+
+    if conf.bind_addresses() != old.bind_addresses() || conf.listen().rebinds(old.listen()) {
+        log::warn!(
+            "bind_address or listen changed, but still listening on {} as before until restarted",
+            old.bind_addresses().join(", ")
+        );
+    }
+
+    Ok((Arc::new(conf), tls_acceptor))
+}
+
+fn reload_certs(old: &conf::Conf) -> Reloaded {
+    let conf = old.reload_certs()?;
+    let tls_acceptor = conf.tls_acceptor()?;
+
+    Ok((Arc::new(conf), tls_acceptor))
+}
+
+// Ticks every cert_check_interval. The tick is ignored while that's 0, but
+// the timer still needs a period.
+fn cert_check_timer(conf: &conf::Conf) -> time::Interval {
+    time::interval(conf.cert_check_interval().max(Duration::from_secs(1)))
+}
+
+// Accepts connections on one address and hands them to the main loop, until
+// the main loop stops taking them.
+async fn accept_loop(tcp_listener: TcpListener, accepted: mpsc::Sender<(TcpStream, SocketAddr)>) {
+    loop {
+        match tcp_listener.accept().await {
+            Ok(v) => {
+                if accepted.send(v).await.is_err() {
+                    return;
                 }
             }
+            Err(e) => log::error!("Could not accept connection: {}", e),
+        }
+    }
+}
+
+fn log_notices(conf: &conf::Conf) {
+    for (level, msg) in conf.notices() {
+        log::log!(*level, "{}", msg);
+    }
+}
+
+fn signal_stream(kind: SignalKind) -> Signal {
+    match signal(kind) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("Could not listen for signals: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Gives connections still in flight up to `grace` to finish, then cuts
+// off whatever is left.
+async fn drain(mut connections: JoinSet<()>, grace: Duration) {
+    let in_flight = connections.len();
+    if in_flight == 0 {
+        return;
+    }
+    log::info!(
+        "waiting up to {}s for {} connections to finish",
+        grace.as_secs(),
+        in_flight
+    );
+
+    let mut drained = 0;
+    let wait = async {
+        while connections.join_next().await.is_some() {
+            drained += 1;
+        }
+    };
+    let _ = time::timeout(grace, wait).await;
+
+    let cut_off = connections.len();
+    connections.shutdown().await;
+    log::info!("{} connections drained, {} cut off", drained, cut_off);
+}
 
-            handlers::flush_and_kill(&mut stream, remote_address).await;
+async fn serve(conf: &conf::Conf, stream: &mut TlsStream<TcpStream>, remote_address: SocketAddr) {
+    log::info!("REQ {} :: Connected", remote_address);
+
+    let req_url = match handlers::entrance(conf, stream, remote_address).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("REQ {} :: {}", remote_address, e);
+            if e.code() != response::Code::Unknown {
+                let header = e.code().get_header(e.meta());
+                match stream.write(&header).await {
+                    Ok(_) => (),
+                    Err(e) => {
+                        log::error!("REQ {} :: {}", remote_address, e);
+                    }
+                };
+            }
+            handlers::flush_and_kill(stream, remote_address).await;
             log::info!("REQ {} :: Terminated", remote_address);
-        });
+            return;
+        }
+    };
+
+    if let Err(e) = handlers::route(conf, stream, remote_address, req_url).await {
+        log::error!("REQ {} :: {}", remote_address, e);
+        if e.code() != response::Code::Unknown {
+            let header = e.code().get_header(e.meta());
+            if let Err(e) = stream.write_all(&header).await {
+                log::error!("REQ {} :: {}", remote_address, e);
+            }
+        }
     }
+
+    handlers::flush_and_kill(stream, remote_address).await;
+    log::info!("REQ {} :: Terminated", remote_address);
 }
@@ -0,0 +1,286 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::{self, Certificate, ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::conf::GEMINI_PORT;
+use crate::err::Supernova;
+use crate::response;
+use crate::routes::Route;
+use crate::tls;
+
+/// A request path prefix served by another Gemini server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyRoute {
+    path: String,
+    // gemini://host:port of the backend server
+    backend: String,
+    // hex SHA-256 of the backend's certificate; any certificate is accepted
+    // when this isn't set
+    fingerprint: Option<String>,
+    // seconds to wait for the connection and the response header, and
+    // for each part of the body after it
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+impl ProxyRoute {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if !self.path.starts_with('/') {
+            let msg = format!("proxy path must start with /: {}", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+
+        let backend = self.backend_url()?;
+        if backend.scheme() != "gemini" || backend.host_str().is_none() {
+            let msg = format!("proxy backend must be a gemini:// URL: {}", self.backend);
+            return Err(Supernova::boom(&msg));
+        }
+
+        if let Some(fp) = self.pinned_fingerprint() {
+            if fp.len() != 64 || !fp.chars().all(|c| c.is_ascii_hexdigit()) {
+                let msg = format!("proxy fingerprint is not a SHA-256 hex digest: {}", fp);
+                return Err(Supernova::boom(&msg));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn backend_url(&self) -> Result<Url, Supernova> {
+        Url::parse(&self.backend).map_err(|e| {
+            let msg = format!("invalid proxy backend {}: {}", self.backend, e);
+            Supernova::boom(&msg)
+        })
+    }
+
+    // Fingerprints are compared as lowercase hex without separators, so
+    // "AB:CD:..." copied from openssl works too.
+    fn pinned_fingerprint(&self) -> Option<String> {
+        self.fingerprint
+            .as_ref()
+            .map(|fp| fp.replace(':', "").to_ascii_lowercase())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Route for ProxyRoute {
+    fn path(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+}
+
+// Backends are usually self-signed, so the only check is the optional pin.
+struct PinnedCertVerifier {
+    fingerprint: Option<String>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.fingerprint {
+            Some(fp) if *fp != tls::fingerprint(end_entity) => Err(rustls::Error::General(
+                String::from("backend certificate does not match pinned fingerprint"),
+            )),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Forwards the request line, unchanged, to the backend behind `route`.
+/// Returns the status line of the backend's response and a reader for the
+/// rest of it. Anything that goes wrong on the way is a proxy error.
+pub async fn request(
+    route: &ProxyRoute,
+    req_url: &Url,
+) -> Result<(Vec<u8>, BufReader<TlsStream<TcpStream>>), Supernova> {
+    let proxy_err = |msg: String, meta: &str| {
+        Supernova::boom(&msg)
+            .with_code(response::Code::ProxyError)
+            .with_meta(meta)
+    };
+
+    let backend = route.backend_url()?;
+    let host = backend.host_str().unwrap_or("");
+    let port = backend.port().unwrap_or(GEMINI_PORT);
+    // host_str() keeps the brackets around IPv6 addresses, which neither
+    // the connect nor the server name takes
+    let ip = match backend.host() {
+        Some(url::Host::Ipv4(v)) => Some(IpAddr::V4(v)),
+        Some(url::Host::Ipv6(v)) => Some(IpAddr::V6(v)),
+        _ => None,
+    };
+
+    let exchange = async {
+        let server_name = match ip {
+            Some(v) => ServerName::IpAddress(v),
+            None => ServerName::try_from(host).map_err(|e| {
+                proxy_err(
+                    format!("invalid backend name {}: {}", host, e),
+                    "bad backend",
+                )
+            })?,
+        };
+
+        let tls_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: route.pinned_fingerprint(),
+            }))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(tls_config));
+
+        let tcp = match ip {
+            Some(v) => TcpStream::connect((v, port)).await,
+            None => TcpStream::connect((host, port)).await,
+        };
+        let tcp = tcp.map_err(|e| {
+            let msg = format!("could not connect to backend {}: {}", route.backend, e);
+            proxy_err(msg, "backend unavailable")
+        })?;
+        let mut backend_stream = connector.connect(server_name, tcp).await.map_err(|e| {
+            let msg = format!("TLS with backend {} failed: {}", route.backend, e);
+            proxy_err(msg, "backend TLS handshake failed")
+        })?;
+
+        let request = format!("{}\r\n", req_url);
+        backend_stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|e| {
+                let msg = format!("could not write to backend {}: {}", route.backend, e);
+                proxy_err(msg, "backend unavailable")
+            })?;
+
+        let mut reader = BufReader::new(backend_stream);
+        match response::read_status_line(&mut reader).await {
+            Ok(Some(header)) => Ok((header, reader)),
+            Ok(None) => {
+                let msg = format!("malformed response from backend {}", route.backend);
+                Err(proxy_err(msg, "malformed response from backend"))
+            }
+            Err(e) => {
+                let msg = format!("could not read from backend {}: {}", route.backend, e);
+                Err(proxy_err(msg, "backend unavailable"))
+            }
+        }
+    };
+
+    match time::timeout(route.timeout(), exchange).await {
+        Ok(v) => v,
+        Err(_) => {
+            let msg = format!(
+                "backend {} timed out after {}s",
+                route.backend, route.timeout
+            );
+            Err(proxy_err(msg, "backend timed out"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+
+    fn route(path: &str, backend: &str, fingerprint: Option<&str>) -> ProxyRoute {
+        ProxyRoute {
+            path: path.to_string(),
+            backend: backend.to_string(),
+            fingerprint: fingerprint.map(|f| f.to_string()),
+            timeout: 1,
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert!(route("/app", "gemini://localhost:1966", None)
+            .validate()
+            .is_ok());
+        assert!(route("app", "gemini://localhost:1966", None)
+            .validate()
+            .is_err());
+        assert!(route("/app", "https://localhost", None).validate().is_err());
+        assert!(route("/app", "not a url", None).validate().is_err());
+        assert!(route("/app", "gemini://[::1]:1966", None)
+            .validate()
+            .is_ok());
+
+        let fp = "AB:".repeat(31) + "AB";
+        let pinned = route("/app", "gemini://localhost", Some(&fp));
+        assert!(pinned.validate().is_ok());
+        assert_eq!(pinned.pinned_fingerprint().unwrap(), "ab".repeat(32));
+        assert!(route("/app", "gemini://localhost", Some("abcd"))
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn routes() {
+        let routes = vec![
+            route("/", "gemini://a", None),
+            route("/app/", "gemini://b", None),
+        ];
+        assert_eq!(routes::find(&routes, "/x").unwrap().backend, "gemini://a");
+        assert_eq!(
+            routes::find(&routes, "/app/x").unwrap().backend,
+            "gemini://b"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_backend() {
+        let url = Url::parse("gemini://example.com/app").unwrap();
+        let err = request(&route("/app", "gemini://127.0.0.1:1", None), &url)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), response::Code::ProxyError);
+        assert_eq!(err.meta(), "backend unavailable");
+
+        let err = request(&route("/app", "gemini://[::1]:1", None), &url)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.meta(), "backend unavailable");
+    }
+}
@@ -0,0 +1,489 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::Certificate;
+
+use crate::err::Supernova;
+use crate::file;
+use crate::response;
+use crate::tls;
+
+/// Settings for the per-client request rate limit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConf {
+    enabled: bool,
+    // requests a client may make in a burst
+    requests: u32,
+    // seconds it takes for a client's full allowance to come back
+    per_seconds: u64,
+    // clients in the same subnet of this size share one allowance
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    // clients that send a certificate are also held to an allowance
+    // keyed by its fingerprint, on top of the one for their address
+    by_certificate: bool,
+    // how many clients are tracked at once
+    max_clients: usize,
+    // different limits below some paths
+    paths: Vec<PathLimit>,
+}
+
+impl Default for RateLimitConf {
+    fn default() -> Self {
+        RateLimitConf {
+            enabled: false,
+            requests: 60,
+            per_seconds: 60,
+            ipv4_prefix: 32,
+            ipv6_prefix: 128,
+            by_certificate: false,
+            max_clients: 10000,
+            paths: Vec::new(),
+        }
+    }
+}
+
+/// A limit for requests below `path`, counted separately from the rest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathLimit {
+    path: String,
+    requests: u32,
+    per_seconds: u64,
+}
+
+impl PathLimit {
+    fn path(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+}
+
+impl RateLimitConf {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if self.requests == 0 || self.per_seconds == 0 {
+            return Err(Supernova::boom(
+                "rate limit requests and per_seconds must be above 0",
+            ));
+        }
+
+        for limit in &self.paths {
+            if !limit.path.starts_with('/') {
+                let msg = format!("rate limit path must start with /: {}", limit.path);
+                return Err(Supernova::boom(&msg));
+            }
+            if limit.requests == 0 || limit.per_seconds == 0 {
+                let msg = format!(
+                    "rate limit requests and per_seconds for {} must be above 0",
+                    limit.path
+                );
+                return Err(Supernova::boom(&msg));
+            }
+        }
+
+        if self.ipv4_prefix > 32 || self.ipv6_prefix > 128 {
+            let msg = format!(
+                "rate limit prefixes out of range: /{} and /{}",
+                self.ipv4_prefix, self.ipv6_prefix
+            );
+            return Err(Supernova::boom(&msg));
+        }
+
+        if self.max_clients == 0 {
+            return Err(Supernova::boom("rate limit max_clients must be at least 1"));
+        }
+
+        Ok(())
+    }
+
+    // Path of the longest path limit covering `req_path`, if any.
+    fn path_limit(&self, req_path: &str) -> Option<&str> {
+        self.paths
+            .iter()
+            .map(|l| l.path())
+            .filter(|path| file::path_under(req_path, path))
+            .max_by_key(|path| path.len())
+    }
+
+    fn limit(&self, path_limit: Option<&str>) -> (u32, u64) {
+        match self.paths.iter().find(|l| Some(l.path()) == path_limit) {
+            Some(l) => (l.requests, l.per_seconds),
+            None => (self.requests, self.per_seconds),
+        }
+    }
+
+    // Every allowance a request counts against. New certificates cost
+    // nothing to make, so the address is always one of them.
+    fn client_keys(&self, ip: IpAddr, cert: Option<&Certificate>) -> Vec<ClientKey> {
+        let mut keys = vec![self.address_key(ip)];
+        if let (true, Some(cert)) = (self.by_certificate, cert) {
+            keys.push(ClientKey::Certificate(tls::fingerprint(cert)));
+        }
+
+        keys
+    }
+
+    fn address_key(&self, ip: IpAddr) -> ClientKey {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => {
+                let bits = u32::from(v4);
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.ipv4_prefix))
+                    .unwrap_or(0);
+                ClientKey::Address(IpAddr::from((bits & mask).to_be_bytes()))
+            }
+            IpAddr::V6(v6) => {
+                let bits = u128::from(v6);
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.ipv6_prefix))
+                    .unwrap_or(0);
+                ClientKey::Address(IpAddr::from((bits & mask).to_be_bytes()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientKey {
+    Address(IpAddr),
+    Certificate(String),
+}
+
+// Keyed by the path of the limit in use, if any, and the client.
+type Buckets = HashMap<(Option<String>, ClientKey), Bucket>;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    // Tops the bucket up for the time since it was last used.
+    fn refill(&mut self, now: Instant, capacity: f64, rate: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+}
+
+/// Token buckets for every client seen recently, shared by all connections.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    conf: RateLimitConf,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(conf: RateLimitConf) -> RateLimiter {
+        RateLimiter {
+            conf,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// This limiter's settings over the clients `old` has seen. Used on
+    /// reload, so clients don't get their allowance back by waiting for one.
+    pub fn carry_over(&self, old: &RateLimiter) -> RateLimiter {
+        RateLimiter {
+            conf: self.conf.clone(),
+            buckets: old.buckets.clone(),
+        }
+    }
+
+    /// Takes one request from the client's allowance. Clients that have
+    /// run out get 44 with the number of seconds until they can try again.
+    pub fn check(
+        &self,
+        ip: IpAddr,
+        cert: Option<&Certificate>,
+        req_path: &str,
+    ) -> Result<(), Supernova> {
+        if !self.conf.enabled {
+            return Ok(());
+        }
+        self.take(ip, cert, req_path, Instant::now())
+    }
+
+    fn take(
+        &self,
+        ip: IpAddr,
+        cert: Option<&Certificate>,
+        req_path: &str,
+        now: Instant,
+    ) -> Result<(), Supernova> {
+        let path_limit = self.conf.path_limit(req_path);
+        let (requests, per_seconds) = self.conf.limit(path_limit);
+        let capacity = f64::from(requests);
+        let rate = capacity / per_seconds as f64;
+        let keys: Vec<_> = self
+            .conf
+            .client_keys(ip, cert)
+            .into_iter()
+            .map(|k| (path_limit.map(String::from), k))
+            .collect();
+
+        let mut buckets = match self.buckets.lock() {
+            Ok(v) => v,
+            Err(e) => e.into_inner(),
+        };
+
+        // the request only goes through if every allowance has room for it
+        let mut short = 0.0_f64;
+        for key in &keys {
+            if !buckets.contains_key(key) && buckets.len() >= self.conf.max_clients {
+                self.evict(&mut buckets, now);
+            }
+
+            let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+                tokens: capacity,
+                updated: now,
+            });
+            bucket.refill(now, capacity, rate);
+            short = short.max(1.0 - bucket.tokens);
+        }
+
+        if short <= 0.0 {
+            for key in &keys {
+                if let Some(bucket) = buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+            return Ok(());
+        }
+
+        let wait = Duration::from_secs_f64(short / rate);
+        let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        let msg = format!("rate limit exceeded, retry in {}s", seconds);
+        Err(Supernova::boom(&msg)
+            .with_code(response::Code::SlowDown)
+            .with_meta(&seconds.to_string()))
+    }
+
+    // Makes room for a new client. Buckets that have filled back up are
+    // the same as new ones, so they go first; if that isn't enough, the
+    // client seen least recently is forgotten.
+    fn evict(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.retain(|(path_limit, _), bucket| {
+            let (requests, per_seconds) = self.conf.limit(path_limit.as_deref());
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            let rate = f64::from(requests) / per_seconds as f64;
+            bucket.tokens + elapsed * rate < f64::from(requests)
+        });
+
+        if buckets.len() < self.conf.max_clients {
+            return;
+        }
+
+        let oldest = buckets
+            .iter()
+            .min_by_key(|(_, bucket)| bucket.updated)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            buckets.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(requests: u32, per_seconds: u64) -> RateLimitConf {
+        RateLimitConf {
+            enabled: true,
+            requests,
+            per_seconds,
+            ..RateLimitConf::default()
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        let limiter = RateLimiter::new(conf(2, 10));
+        let start = Instant::now();
+        let client = ip("192.0.2.1");
+
+        assert!(limiter.take(client, None, "/", start).is_ok());
+        assert!(limiter.take(client, None, "/", start).is_ok());
+        let err = limiter.take(client, None, "/", start).unwrap_err();
+        assert_eq!(err.code(), response::Code::SlowDown);
+        assert_eq!(err.meta(), "5");
+
+        // other clients have their own allowance
+        assert!(limiter.take(ip("192.0.2.2"), None, "/", start).is_ok());
+
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.take(client, None, "/", later).is_ok());
+        assert!(limiter.take(client, None, "/", later).is_err());
+    }
+
+    #[test]
+    fn path_limits() {
+        let mut c = conf(100, 60);
+        c.paths.push(PathLimit {
+            path: String::from("/search/"),
+            requests: 1,
+            per_seconds: 30,
+        });
+        let limiter = RateLimiter::new(c);
+        let now = Instant::now();
+        let client = ip("192.0.2.1");
+
+        assert!(limiter.take(client, None, "/search", now).is_ok());
+        let err = limiter.take(client, None, "/search/q", now).unwrap_err();
+        assert_eq!(err.meta(), "30");
+        assert!(limiter.take(client, None, "/index.gmi", now).is_ok());
+    }
+
+    #[test]
+    fn carry_over() {
+        let mut c = conf(2, 60);
+        c.paths.push(PathLimit {
+            path: String::from("/search"),
+            requests: 1,
+            per_seconds: 30,
+        });
+        let old = RateLimiter::new(c);
+        let now = Instant::now();
+        let client = ip("192.0.2.1");
+        assert!(old.take(client, None, "/search", now).is_ok());
+        assert!(old.take(client, None, "/", now).is_ok());
+        assert!(old.take(client, None, "/", now).is_ok());
+
+        // the path limit moved down the list, and the overall one shrank
+        let mut c = conf(1, 60);
+        c.paths.push(PathLimit {
+            path: String::from("/other"),
+            requests: 5,
+            per_seconds: 30,
+        });
+        c.paths.push(PathLimit {
+            path: String::from("/search/"),
+            requests: 1,
+            per_seconds: 30,
+        });
+        let reloaded = RateLimiter::new(c).carry_over(&old);
+        assert!(reloaded.take(client, None, "/search", now).is_err());
+        assert!(reloaded.take(client, None, "/", now).is_err());
+        assert!(reloaded.take(client, None, "/other", now).is_ok());
+        assert!(old.take(client, None, "/", now).is_err());
+    }
+
+    #[test]
+    fn client_keys() {
+        let mut c = conf(1, 60);
+        c.ipv4_prefix = 24;
+        c.ipv6_prefix = 64;
+        c.by_certificate = true;
+
+        assert_eq!(
+            c.client_keys(ip("192.0.2.77"), None),
+            [ClientKey::Address(ip("192.0.2.0"))]
+        );
+        assert_eq!(
+            c.client_keys(ip("::ffff:192.0.2.77"), None),
+            [ClientKey::Address(ip("192.0.2.0"))]
+        );
+        assert_eq!(
+            c.client_keys(ip("2001:db8:1:2:3:4:5:6"), None),
+            [ClientKey::Address(ip("2001:db8:1:2::"))]
+        );
+
+        let cert = Certificate(b"not really a certificate".to_vec());
+        assert_eq!(
+            c.client_keys(ip("192.0.2.77"), Some(&cert)),
+            [
+                ClientKey::Address(ip("192.0.2.0")),
+                ClientKey::Certificate(tls::fingerprint(&cert))
+            ]
+        );
+
+        c.ipv4_prefix = 0;
+        assert_eq!(
+            c.client_keys(ip("198.51.100.1"), None),
+            [ClientKey::Address(ip("0.0.0.0"))]
+        );
+    }
+
+    #[test]
+    fn certificates() {
+        let mut c = conf(2, 60);
+        c.by_certificate = true;
+        let limiter = RateLimiter::new(c);
+        let now = Instant::now();
+        let first = Certificate(b"first".to_vec());
+        let second = Certificate(b"second".to_vec());
+        let third = Certificate(b"third".to_vec());
+
+        // a new certificate for every request doesn't get around the
+        // allowance of the address
+        let client = ip("192.0.2.1");
+        assert!(limiter.take(client, Some(&first), "/", now).is_ok());
+        assert!(limiter.take(client, Some(&second), "/", now).is_ok());
+        assert!(limiter.take(client, Some(&third), "/", now).is_err());
+        assert!(limiter.take(client, None, "/", now).is_err());
+
+        // and one certificate is held to its own allowance everywhere
+        assert!(limiter
+            .take(ip("192.0.2.2"), Some(&first), "/", now)
+            .is_ok());
+        assert!(limiter
+            .take(ip("192.0.2.3"), Some(&first), "/", now)
+            .is_err());
+        assert!(limiter.take(ip("192.0.2.3"), None, "/", now).is_ok());
+    }
+
+    #[test]
+    fn bounded() {
+        let mut c = conf(1, 60);
+        c.max_clients = 2;
+        let limiter = RateLimiter::new(c);
+        let now = Instant::now();
+
+        for i in 1..=5 {
+            let client = ip(&format!("192.0.2.{}", i));
+            assert!(limiter
+                .take(client, None, "/", now + Duration::from_secs(i))
+                .is_ok());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        // the most recent clients are the ones still remembered
+        let err = limiter.take(ip("192.0.2.5"), None, "/", now + Duration::from_secs(6));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn validation() {
+        assert!(conf(60, 60).validate().is_ok());
+        assert!(conf(0, 60).validate().is_err());
+        assert!(conf(1, 0).validate().is_err());
+
+        let mut c = conf(1, 1);
+        c.ipv6_prefix = 129;
+        assert!(c.validate().is_err());
+    }
+}
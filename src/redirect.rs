@@ -0,0 +1,254 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::conf;
+use crate::err::Supernova;
+use crate::file;
+use crate::response;
+
+/// Sends requests for one path, or everything below it, somewhere else.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Redirect {
+    from: String,
+    // an absolute path on the same host or a full URL
+    to: String,
+    #[serde(default)]
+    permanent: bool,
+    // whether paths below `from` are redirected too, keeping the rest of
+    // the path
+    #[serde(default)]
+    prefix: bool,
+}
+
+impl Redirect {
+    fn validate(&self) -> Result<(), Supernova> {
+        if !self.from.starts_with('/') {
+            let msg = format!("redirect source must start with /: {}", self.from);
+            return Err(Supernova::boom(&msg));
+        }
+        if !self.to.starts_with('/') && Url::parse(&self.to).is_err() {
+            let msg = format!(
+                "redirect target must be an absolute path or a URL: {}",
+                self.to
+            );
+            return Err(Supernova::boom(&msg));
+        }
+
+        Ok(())
+    }
+
+    fn from(&self) -> &str {
+        self.from.trim_end_matches('/')
+    }
+
+    fn code(&self) -> response::Code {
+        if self.permanent {
+            response::Code::RedirectPermanent
+        } else {
+            response::Code::RedirectTemporary
+        }
+    }
+
+    // Where `req_path` goes under this rule, if it matches.
+    fn target(&self, req_path: &str) -> Option<String> {
+        let from = self.from();
+        if self.prefix && file::path_under(req_path, from) {
+            let rest: Vec<String> = req_path[from.len()..]
+                .split('/')
+                .filter(|s| !s.is_empty())
+                .map(file::encode_segment)
+                .collect();
+            if rest.is_empty() {
+                return Some(self.to.clone());
+            }
+            Some(format!(
+                "{}/{}",
+                self.to.trim_end_matches('/'),
+                rest.join("/")
+            ))
+        } else if req_path.trim_end_matches('/') == from {
+            Some(self.to.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Checks a host's redirect rules, including that following them from any
+/// source path on this host never comes back around. `names` are the names
+/// the host answers to, so full URLs pointing back at it are followed too.
+pub fn validate(redirects: &[Redirect], names: &[String]) -> Result<(), Supernova> {
+    for rule in redirects {
+        rule.validate()?;
+    }
+
+    for rule in redirects {
+        let mut seen = HashSet::new();
+        let mut path = rule.from().to_string();
+        while let Some(target) = apply(redirects, &path) {
+            // a prefix rule pointing below itself makes a new path every
+            // time round, so stop once there have been more hops than rules
+            if !seen.insert(path.clone()) || seen.len() > redirects.len() {
+                let msg = format!("redirect loop starting at {}", rule.from);
+                return Err(Supernova::boom(&msg));
+            }
+            path = match local_path(&target, names) {
+                Some(v) => v,
+                None => break,
+            };
+        }
+    }
+
+    Ok(())
+}
+
+fn apply(redirects: &[Redirect], req_path: &str) -> Option<String> {
+    find(redirects, req_path).and_then(|r| r.target(req_path))
+}
+
+// The decoded path of a redirect target, if it stays on this host.
+fn local_path(target: &str, names: &[String]) -> Option<String> {
+    let path = if target.starts_with('/') {
+        target.split(['?', '#']).next().unwrap_or("").to_string()
+    } else {
+        let url = Url::parse(target).ok()?;
+        let host = conf::normalise_hostname(url.host_str()?);
+        if url.scheme() != "gemini" || !names.contains(&host) {
+            return None;
+        }
+        url.path().to_string()
+    };
+
+    file::normalise_path(&path).ok()
+}
+
+// Exact rules win over prefix rules; among prefix rules the longest wins.
+fn find<'a>(redirects: &'a [Redirect], req_path: &str) -> Option<&'a Redirect> {
+    let exact = redirects
+        .iter()
+        .find(|r| !r.prefix && req_path.trim_end_matches('/') == r.from());
+    exact.or_else(|| {
+        redirects
+            .iter()
+            .filter(|r| r.prefix && file::path_under(req_path, r.from()))
+            .max_by_key(|r| r.from().len())
+    })
+}
+
+/// The redirect response for a request, if a rule matches. Relative targets
+/// are resolved against the request URL, and prefix rules carry the query
+/// string over.
+pub fn response(redirects: &[Redirect], req_url: &Url, req_path: &str) -> Option<Vec<u8>> {
+    let rule = find(redirects, req_path)?;
+    let target = rule.target(req_path)?;
+
+    let mut url = match req_url.join(&target) {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("could not build redirect target {}: {}", target, e);
+            return None;
+        }
+    };
+    if rule.prefix && url.query().is_none() {
+        url.set_query(req_url.query());
+    }
+
+    Some(rule.code().get_header(url.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: &str, to: &str, permanent: bool, prefix: bool) -> Redirect {
+        Redirect {
+            from: from.to_string(),
+            to: to.to_string(),
+            permanent,
+            prefix,
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let rules = vec![
+            rule("/old.gmi", "/new.gmi", true, false),
+            rule("/blog/", "gemini://blog.example.org/posts/", false, true),
+            rule("/blog/about", "/about.gmi", false, false),
+        ];
+        let url = |s: &str| Url::parse(s).unwrap();
+
+        assert_eq!(
+            response(&rules, &url("gemini://example.org/old.gmi"), "/old.gmi").unwrap(),
+            b"31 gemini://example.org/new.gmi\r\n"
+        );
+        assert_eq!(
+            response(
+                &rules,
+                &url("gemini://example.org/blog/my%20post?x=1"),
+                "/blog/my post"
+            )
+            .unwrap(),
+            b"30 gemini://blog.example.org/posts/my%20post?x=1\r\n"
+        );
+        assert_eq!(
+            response(&rules, &url("gemini://example.org/blog"), "/blog").unwrap(),
+            b"30 gemini://blog.example.org/posts/\r\n"
+        );
+        assert_eq!(
+            response(
+                &rules,
+                &url("gemini://example.org/blog/about"),
+                "/blog/about"
+            )
+            .unwrap(),
+            b"30 gemini://example.org/about.gmi\r\n"
+        );
+        assert!(response(&rules, &url("gemini://example.org/blogs"), "/blogs").is_none());
+    }
+
+    #[test]
+    fn validation() {
+        let names = vec![String::from("example.org")];
+
+        let ok = vec![
+            rule("/a", "/b", false, false),
+            rule("/b", "gemini://example.org/c", false, false),
+            rule("/c", "gemini://elsewhere.example/a", false, false),
+        ];
+        assert!(validate(&ok, &names).is_ok());
+
+        let looped = vec![
+            rule("/a", "/b", false, false),
+            rule("/b", "gemini://EXAMPLE.org/a", false, false),
+        ];
+        assert!(validate(&looped, &names).is_err());
+
+        let self_loop = vec![rule("/docs", "/docs/", true, false)];
+        assert!(validate(&self_loop, &names).is_err());
+
+        let prefix_loop = vec![rule("/x", "/x/y", false, true)];
+        assert!(validate(&prefix_loop, &names).is_err());
+
+        assert!(validate(&[rule("a", "/b", false, false)], &names).is_err());
+        assert!(validate(&[rule("/a", "b c", false, false)], &names).is_err());
+    }
+}
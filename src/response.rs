@@ -15,9 +15,16 @@
  */
 
 use std::fmt;
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
 
 pub const GEMINI_MIME: &str = "text/gemini";
 
+// Longest status line we accept: two digits, a space, 1024 bytes of meta
+// and the CRLF.
+const MAX_STATUS_LINE_BYTES: u64 = 1029;
+
 // Response codes
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -45,8 +52,8 @@ pub enum Code {
 
 impl Code {
     pub fn get_header(&self, meta: &str) -> Vec<u8> {
-        let msg = if *self == Code::Success {
-            format!("{} {}\r\n", self, meta)
+        let msg = if *self == Code::Success || !meta.is_empty() {
+            format!("{} {}\r\n", *self as u8, meta)
         } else {
             format!("{}\r\n", self)
         };
@@ -84,6 +91,34 @@ impl fmt::Display for Code {
     }
 }
 
+// A response header is two digits, then a space or the end of the line.
+fn has_status_line(out: &[u8]) -> bool {
+    out.len() >= 4
+        && out[0].is_ascii_digit()
+        && out[1].is_ascii_digit()
+        && (out[2] == b' ' || out[2..].starts_with(b"\r\n"))
+}
+
+/// Reads the status line a CGI script or backend starts its response with,
+/// CRLF included, leaving the body in `reader`. `None` means the response
+/// doesn't start with a valid one.
+pub async fn read_status_line<R>(reader: &mut R) -> io::Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    reader
+        .take(MAX_STATUS_LINE_BYTES)
+        .read_until(b'\n', &mut line)
+        .await?;
+
+    if has_status_line(&line) && line.ends_with(b"\r\n") {
+        Ok(Some(line))
+    } else {
+        Ok(None)
+    }
+}
+
 // Appended to the bottom of .gmi files
 pub fn footer_bytes<'a>() -> &'a [u8] {
     "\n\n~~~~ served by laika ~~~~~~~~~\nhttps://sr.ht/~gbmor/laika\n\n".as_bytes()
@@ -172,4 +207,42 @@ mod tests {
         assert!(cert_not_valid.ends_with("\r\n".as_bytes()));
         assert_eq!(&cert_not_valid[0..2], "62".as_bytes());
     }
+
+    #[test]
+    fn header_meta() {
+        assert_eq!(
+            Code::Success.get_header("text/gemini"),
+            b"20 text/gemini\r\n"
+        );
+        assert_eq!(Code::NotFound.get_header(""), b"51 NOT FOUND\r\n");
+        assert_eq!(
+            Code::ProxyRequestRefused.get_header("not served here"),
+            b"53 not served here\r\n"
+        );
+    }
+
+    #[test]
+    fn status_lines() {
+        assert!(has_status_line(b"20 text/gemini\r\n"));
+        assert!(has_status_line(b"51\r\n"));
+        assert!(!has_status_line(b"hello\r\n"));
+        assert!(!has_status_line(b"2x text/gemini\r\n"));
+        assert!(!has_status_line(b""));
+    }
+
+    #[tokio::test]
+    async fn status_line_reading() {
+        let mut reader = &b"20 text/gemini\r\nbody"[..];
+        let line = read_status_line(&mut reader).await.unwrap();
+        assert_eq!(line.unwrap(), b"20 text/gemini\r\n");
+        assert_eq!(reader, b"body");
+
+        let long = format!("20 {}\r\n", "x".repeat(1025));
+        let mut reader = long.as_bytes();
+        assert!(read_status_line(&mut reader).await.unwrap().is_none());
+        let mut reader = &b"20 text/gemini\n"[..];
+        assert!(read_status_line(&mut reader).await.unwrap().is_none());
+        let mut reader = &b"20 text/gemini"[..];
+        assert!(read_status_line(&mut reader).await.unwrap().is_none());
+    }
 }
@@ -0,0 +1,51 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use crate::file;
+
+/// Something configured for a request path and everything below it.
+pub trait Route {
+    /// The configured path, without a trailing slash.
+    fn path(&self) -> &str;
+}
+
+/// Picks the route with the longest path that `req_path` falls under.
+pub fn find<'a, R: Route>(routes: &'a [R], req_path: &str) -> Option<&'a R> {
+    routes
+        .iter()
+        .filter(|r| file::path_under(req_path, r.path()))
+        .max_by_key(|r| r.path().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Route for &str {
+        fn path(&self) -> &str {
+            self.trim_end_matches('/')
+        }
+    }
+
+    #[test]
+    fn longest_match() {
+        let routes = ["/", "/app/", "/app/admin"];
+        assert_eq!(find(&routes, "/app/admin/x"), Some(&"/app/admin"));
+        assert_eq!(find(&routes, "/app"), Some(&"/app/"));
+        assert_eq!(find(&routes, "/apple"), Some(&"/"));
+        assert_eq!(find(&routes[1..], "/apple"), None);
+    }
+}
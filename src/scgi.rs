@@ -0,0 +1,226 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::time;
+
+use crate::err::Supernova;
+use crate::response;
+use crate::routes::Route;
+
+/// A request path prefix handed off to an SCGI application.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScgiRoute {
+    path: String,
+    // "unix:/path/to/socket" or "host:port"
+    address: String,
+    // seconds to wait for the connection and the response header, and
+    // for each part of the body after it
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+pub trait Backend: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Backend for T {}
+
+impl ScgiRoute {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        if !self.path.starts_with('/') {
+            let msg = format!("SCGI path must start with /: {}", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+        if self.address.is_empty() || self.address == "unix:" {
+            let msg = format!("SCGI route {} has no address", self.path);
+            return Err(Supernova::boom(&msg));
+        }
+
+        Ok(())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    async fn connect(&self) -> Result<Box<dyn Backend>, std::io::Error> {
+        match self.address.strip_prefix("unix:") {
+            Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            None => Ok(Box::new(TcpStream::connect(&self.address).await?)),
+        }
+    }
+}
+
+impl Route for ScgiRoute {
+    fn path(&self) -> &str {
+        self.path.trim_end_matches('/')
+    }
+}
+
+/// Sends a request to the application behind `route`. Returns the status
+/// line of its response and a reader for the rest of it. Failing to reach
+/// the application is a proxy error; a reply without a valid status line is
+/// a CGI error.
+pub async fn request(
+    route: &ScgiRoute,
+    env: Vec<(String, String)>,
+) -> Result<(Vec<u8>, BufReader<Box<dyn Backend>>), Supernova> {
+    let exchange = async {
+        let mut backend = match route.connect().await {
+            Ok(v) => v,
+            Err(e) => {
+                let msg = format!("could not connect to SCGI backend {}: {}", route.address, e);
+                return Err(Supernova::boom(&msg)
+                    .with_code(response::Code::ProxyError)
+                    .with_meta("backend unavailable"));
+            }
+        };
+
+        if let Err(e) = backend.write_all(&encode_headers(&env)).await {
+            let msg = format!("could not write to SCGI backend {}: {}", route.address, e);
+            return Err(Supernova::boom(&msg)
+                .with_code(response::Code::ProxyError)
+                .with_meta("backend unavailable"));
+        }
+
+        let mut reader = BufReader::new(backend);
+        match response::read_status_line(&mut reader).await {
+            Ok(Some(header)) => Ok((header, reader)),
+            Ok(None) => {
+                let msg = format!("malformed response from SCGI backend {}", route.address);
+                Err(Supernova::boom(&msg).with_code(response::Code::CgiError))
+            }
+            Err(e) => {
+                let msg = format!("could not read from SCGI backend {}: {}", route.address, e);
+                Err(Supernova::boom(&msg).with_code(response::Code::CgiError))
+            }
+        }
+    };
+
+    match time::timeout(route.timeout(), exchange).await {
+        Ok(v) => v,
+        Err(_) => {
+            let msg = format!(
+                "SCGI backend {} timed out after {}s",
+                route.address, route.timeout
+            );
+            Err(Supernova::boom(&msg)
+                .with_code(response::Code::ProxyError)
+                .with_meta("backend timed out"))
+        }
+    }
+}
+
+// Requests carry no body, so CONTENT_LENGTH is always 0. The spec wants it
+// first, followed by SCGI.
+fn encode_headers(env: &[(String, String)]) -> Vec<u8> {
+    let mut headers: Vec<u8> = b"CONTENT_LENGTH\x000\x00SCGI\x001\x00".to_vec();
+    for (k, v) in env {
+        headers.extend_from_slice(k.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(v.as_bytes());
+        headers.push(0);
+    }
+
+    let mut netstring = format!("{}:", headers.len()).into_bytes();
+    netstring.extend_from_slice(&headers);
+    netstring.push(b',');
+
+    netstring
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::routes;
+    use tokio::io::AsyncReadExt;
+
+    use tokio::net::TcpListener;
+
+    fn route(path: &str, address: &str) -> ScgiRoute {
+        ScgiRoute {
+            path: path.to_string(),
+            address: address.to_string(),
+            timeout: 1,
+        }
+    }
+
+    #[test]
+    fn netstring() {
+        let env = vec![(String::from("PATH_INFO"), String::from("/x"))];
+        assert_eq!(
+            encode_headers(&env),
+            b"37:CONTENT_LENGTH\x000\x00SCGI\x001\x00PATH_INFO\x00/x\x00,"
+        );
+    }
+
+    #[test]
+    fn routes() {
+        let routes = vec![route("/app", "a:1"), route("/app/admin/", "b:1")];
+        assert_eq!(routes::find(&routes, "/app").unwrap().address, "a:1");
+        assert_eq!(routes::find(&routes, "/app/x").unwrap().address, "a:1");
+        assert_eq!(
+            routes::find(&routes, "/app/admin/x").unwrap().address,
+            "b:1"
+        );
+        assert!(routes::find(&routes, "/apple").is_none());
+
+        assert!(route("app", "a:1").validate().is_err());
+        assert!(route("/app", "unix:").validate().is_err());
+    }
+
+    async fn backend(reply: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = sock.read(&mut buf).await;
+            sock.write_all(reply).await.unwrap();
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn responses() {
+        let address = backend(b"20 text/gemini\r\n# hi\n").await;
+        let (header, mut body) = request(&route("/", &address), Vec::new()).await.unwrap();
+        assert_eq!(header, b"20 text/gemini\r\n");
+        let mut rest = String::new();
+        body.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "# hi\n");
+
+        let address = backend(b"Status: 200 OK\r\n\r\n").await;
+        let err = request(&route("/", &address), Vec::new()).await.err();
+        assert_eq!(err.unwrap().code(), response::Code::CgiError);
+
+        let address = backend(b"").await;
+        let err = request(&route("/", &address), Vec::new()).await.err();
+        assert_eq!(err.unwrap().code(), response::Code::CgiError);
+
+        let err = request(&route("/", "unix:/nonexistent/laika.sock"), Vec::new())
+            .await
+            .err();
+        assert_eq!(err.unwrap().code(), response::Code::ProxyError);
+    }
+}
@@ -0,0 +1,647 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ring::{digest, signature};
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::server::{
+    ClientCertVerified, ClientCertVerifier, ClientHello, NoServerSessionStorage,
+    ResolvesServerCert, ServerSessionMemoryCache,
+};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    self, Certificate, DistinguishedName, PrivateKey, ServerConfig, SignatureScheme,
+    SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::LazyConfigAcceptor;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::prelude::{FromDer, GeneralName};
+
+use crate::conf::{self, Host};
+use crate::err::Supernova;
+
+/// Protocol settings for a host's TLS connections. Empty lists leave the
+/// choice to rustls.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TlsConf {
+    // "1.2" or "1.3"; Gemini needs at least 1.2
+    #[serde(deserialize_with = "version_name")]
+    min_version: String,
+    #[serde(deserialize_with = "version_name")]
+    max_version: String,
+    // names as in the IANA registry, e.g. TLS13_AES_256_GCM_SHA384 or
+    // TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+    cipher_suites: Vec<String>,
+    // X25519, secp256r1, secp384r1
+    kx_groups: Vec<String>,
+    alpn: Vec<String>,
+    // stateless resumption with tickets encrypted under a key that rotates
+    // every six hours
+    session_tickets: bool,
+    // sessions remembered for stateful resumption; 0 turns it off
+    session_cache: usize,
+}
+
+impl Default for TlsConf {
+    fn default() -> Self {
+        TlsConf {
+            min_version: String::from("1.2"),
+            max_version: String::from("1.3"),
+            cipher_suites: Vec::new(),
+            kx_groups: Vec::new(),
+            alpn: Vec::new(),
+            session_tickets: true,
+            session_cache: 256,
+        }
+    }
+}
+
+impl TlsConf {
+    pub fn validate(&self) -> Result<(), Supernova> {
+        self.versions()?;
+        self.suites()?;
+        self.kx_groups()?;
+
+        Ok(())
+    }
+
+    fn versions(&self) -> Result<Vec<&'static SupportedProtocolVersion>, Supernova> {
+        let min = version(&self.min_version)?;
+        let max = version(&self.max_version)?;
+        if min > max {
+            let msg = format!(
+                "TLS min_version {} is above max_version {}",
+                self.min_version, self.max_version
+            );
+            return Err(Supernova::boom(&msg));
+        }
+
+        Ok(rustls::ALL_VERSIONS
+            .iter()
+            .filter(|v| (min..=max).contains(&v.version.get_u16()))
+            .copied()
+            .collect())
+    }
+
+    fn suites(&self) -> Result<Vec<SupportedCipherSuite>, Supernova> {
+        if self.cipher_suites.is_empty() {
+            return Ok(rustls::DEFAULT_CIPHER_SUITES.to_vec());
+        }
+        self.cipher_suites
+            .iter()
+            .map(|name| {
+                rustls::ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|s| format!("{:?}", s.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| {
+                        let msg = format!("unknown TLS cipher suite: {}", name);
+                        Supernova::boom(&msg)
+                    })
+            })
+            .collect()
+    }
+
+    fn kx_groups(&self) -> Result<Vec<&'static SupportedKxGroup>, Supernova> {
+        if self.kx_groups.is_empty() {
+            return Ok(rustls::ALL_KX_GROUPS.to_vec());
+        }
+        self.kx_groups
+            .iter()
+            .map(|name| {
+                rustls::ALL_KX_GROUPS
+                    .iter()
+                    .find(|g| format!("{:?}", g.name).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| {
+                        let msg = format!("unknown TLS key exchange group: {}", name);
+                        Supernova::boom(&msg)
+                    })
+            })
+            .collect()
+    }
+
+    // Only hosts with client certificate routes ask for a certificate;
+    // some clients prompt for one whenever they're asked.
+    fn server_config(
+        &self,
+        resolver: Arc<HostCertResolver>,
+        client_auth: bool,
+    ) -> Result<ServerConfig, Supernova> {
+        let builder = ServerConfig::builder()
+            .with_cipher_suites(&self.suites()?)
+            .with_kx_groups(&self.kx_groups()?)
+            .with_protocol_versions(&self.versions()?);
+        let mut config = match builder {
+            Ok(v) if client_auth => v
+                .with_client_cert_verifier(Arc::new(AnyClientCert))
+                .with_cert_resolver(resolver),
+            Ok(v) => v.with_no_client_auth().with_cert_resolver(resolver),
+            Err(e) => {
+                let msg = format!("unusable TLS settings: {}", e);
+                return Err(Supernova::boom(&msg));
+            }
+        };
+
+        config.alpn_protocols = self.alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        if self.session_tickets {
+            config.ticketer = match rustls::Ticketer::new() {
+                Ok(v) => v,
+                Err(e) => {
+                    let msg = format!("could not set up TLS session tickets: {}", e);
+                    return Err(Supernova::boom(&msg));
+                }
+            };
+        }
+        config.session_storage = if self.session_cache == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(self.session_cache)
+        };
+
+        Ok(config)
+    }
+}
+
+// Unquoted, YAML reads 1.3 as a number, so take that as well.
+fn version_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Name {
+        Text(String),
+        Number(f64),
+    }
+
+    match Name::deserialize(deserializer)? {
+        Name::Text(v) => Ok(v),
+        Name::Number(v) => Ok(v.to_string()),
+    }
+}
+
+fn version(name: &str) -> Result<u16, Supernova> {
+    match name {
+        "1.2" => Ok(rustls::ProtocolVersion::TLSv1_2.get_u16()),
+        "1.3" => Ok(rustls::ProtocolVersion::TLSv1_3.get_u16()),
+        _ => {
+            let msg = format!("unsupported TLS version {}: use 1.2 or 1.3", name);
+            Err(Supernova::boom(&msg))
+        }
+    }
+}
+
+/// Finishes TLS handshakes with the settings of the host the client named
+/// in SNI, falling back to the default host's.
+#[derive(Clone)]
+pub struct Acceptor {
+    default: Arc<ServerConfig>,
+    by_name: Arc<HashMap<String, Arc<ServerConfig>>>,
+}
+
+impl Acceptor {
+    pub fn new(default_host: &Host, hosts: &[Host]) -> Result<Acceptor, Supernova> {
+        let resolver = Arc::new(HostCertResolver::new(default_host, hosts)?);
+        let client_auth = !default_host.client_certificates().is_empty();
+        let default = Arc::new(
+            default_host
+                .tls()
+                .server_config(resolver.clone(), client_auth)?,
+        );
+        let mut by_name = HashMap::new();
+        for host in hosts {
+            let client_auth = !host.client_certificates().is_empty();
+            let config = Arc::new(host.tls().server_config(resolver.clone(), client_auth)?);
+            for name in host.names() {
+                by_name.insert(name.clone(), config.clone());
+            }
+        }
+
+        Ok(Acceptor {
+            default,
+            by_name: Arc::new(by_name),
+        })
+    }
+
+    pub async fn accept<IO>(&self, stream: IO) -> io::Result<TlsStream<IO>>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream).await?;
+        let config = start
+            .client_hello()
+            .server_name()
+            .and_then(|name| self.by_name.get(&conf::normalise_hostname(name)))
+            .unwrap_or(&self.default)
+            .clone();
+
+        start.into_stream(config).await
+    }
+}
+
+/// Picks the certificate for a connection from the SNI hostname the client
+/// sent. Clients that don't send SNI, or ask for a name we don't know, get
+/// the default certificate.
+pub struct HostCertResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl HostCertResolver {
+    pub fn new<'a, I>(default_host: &Host, hosts: I) -> Result<HostCertResolver, Supernova>
+    where
+        I: IntoIterator<Item = &'a Host>,
+    {
+        let default = certified_key(default_host)?;
+        let mut by_name = HashMap::new();
+        for host in hosts {
+            let cert = certified_key(host)?;
+            for name in host.names() {
+                by_name.insert(name.clone(), cert.clone());
+            }
+        }
+
+        Ok(HostCertResolver { default, by_name })
+    }
+}
+
+impl ResolvesServerCert for HostCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&conf::normalise_hostname(name)))
+            .unwrap_or(&self.default);
+
+        Some(cert.clone())
+    }
+}
+
+/// Asks the client for a certificate without requiring one, and takes
+/// whatever it sends. Gemini client certificates are usually self-signed,
+/// so there is nothing to check them against here; the handshake still
+/// proves the client holds the key, and paths that care check the
+/// certificate itself once the request is in.
+pub struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Notices when certificate or key files change on disk. A change is only
+/// reported once the files have stayed the same for a whole check, so a
+/// certificate and key being rewritten one after the other are picked up
+/// together, not half-way through.
+pub struct CertWatch {
+    seen: HashMap<PathBuf, Option<SystemTime>>,
+    pending: bool,
+}
+
+impl CertWatch {
+    pub fn new(paths: &[&Path]) -> CertWatch {
+        CertWatch {
+            seen: modified(paths),
+            pending: false,
+        }
+    }
+
+    /// Whether the files changed since the last reported change and have
+    /// settled since.
+    pub fn changed(&mut self, paths: &[&Path]) -> bool {
+        let current = modified(paths);
+        if current != self.seen {
+            self.seen = current;
+            self.pending = true;
+            return false;
+        }
+
+        std::mem::take(&mut self.pending)
+    }
+}
+
+// Files that can't be read have no time, so they count as changed once
+// they show up again.
+fn modified(paths: &[&Path]) -> HashMap<PathBuf, Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|p| {
+            let mtime = fs::metadata(p).and_then(|m| m.modified()).ok();
+            (p.to_path_buf(), mtime)
+        })
+        .collect()
+}
+
+/// The DNS names a certificate was issued for: its subjectAltName entries,
+/// or its subject CN if it has none.
+pub fn cert_names(cert: &Certificate) -> Vec<String> {
+    let x509 = match X509Certificate::from_der(&cert.0) {
+        Ok((_, v)) => v,
+        Err(_) => return Vec::new(),
+    };
+
+    let sans: Vec<String> = match x509.subject_alternative_name() {
+        Ok(Some(ext)) => ext
+            .value
+            .general_names
+            .iter()
+            .filter_map(|n| match n {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if !sans.is_empty() {
+        return sans;
+    }
+
+    x509.subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string())
+        .collect()
+}
+
+/// Hex-encoded SHA-256 digest of a DER certificate.
+pub fn fingerprint(cert: &Certificate) -> String {
+    digest::digest(&digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Makes sure `key` is the private half of the public key in `cert`, by
+/// signing something with it and checking the signature against the
+/// certificate.
+pub fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<(), Supernova> {
+    let signing_key = match sign::any_supported_type(key) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("unusable key: {}", e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+    let signer = match signing_key.choose_scheme(&[
+        SignatureScheme::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256,
+    ]) {
+        Some(v) => v,
+        None => return Err(Supernova::boom("unsupported key type")),
+    };
+    let verify_alg: &dyn signature::VerificationAlgorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &signature::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &signature::ECDSA_P256_SHA256_ASN1,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &signature::ECDSA_P384_SHA384_ASN1,
+        _ => &signature::RSA_PSS_2048_8192_SHA256,
+    };
+
+    let x509 = match X509Certificate::from_der(&cert.0) {
+        Ok((_, v)) => v,
+        Err(e) => {
+            let msg = format!("could not parse certificate: {}", e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+
+    let message = b"laika key check";
+    let sig = match signer.sign(message) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("could not sign with key: {}", e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+    let public_key = &x509.public_key().subject_public_key.data;
+    match signature::UnparsedPublicKey::new(verify_alg, public_key).verify(message, &sig) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(Supernova::boom("the key is not the certificate's")),
+    }
+}
+
+fn certified_key(host: &Host) -> Result<Arc<CertifiedKey>, Supernova> {
+    let signing_key = match sign::any_supported_type(&host.tls_key()) {
+        Ok(v) => v,
+        Err(e) => {
+            let msg = format!("Unusable TLS key for host {}: {}", host.hostname(), e);
+            return Err(Supernova::boom(&msg));
+        }
+    };
+
+    Ok(Arc::new(CertifiedKey::new(host.tls_cert(), signing_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn cert_watch() {
+        let dir = std::env::temp_dir().join(format!("laika-certwatch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::write(&cert, "old").unwrap();
+        std::fs::write(&key, "old").unwrap();
+        let paths = [cert.as_path(), key.as_path()];
+
+        let mut watch = CertWatch::new(&paths);
+        assert!(!watch.changed(&paths));
+
+        let touch = |path: &Path, secs: u64| {
+            let fd = std::fs::File::options().write(true).open(path).unwrap();
+            fd.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        // nothing is reported while the files are still changing
+        touch(&cert, 1);
+        assert!(!watch.changed(&paths));
+        touch(&key, 1);
+        assert!(!watch.changed(&paths));
+        assert!(watch.changed(&paths));
+        assert!(!watch.changed(&paths));
+
+        std::fs::remove_file(&key).unwrap();
+        assert!(!watch.changed(&paths));
+        assert!(watch.changed(&paths));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tls_conf() {
+        let tls: TlsConf = serde_yaml::from_str("min_version: \"1.3\"").unwrap();
+        let versions = tls.versions().unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version, rustls::ProtocolVersion::TLSv1_3);
+        assert_eq!(TlsConf::default().versions().unwrap().len(), 2);
+        let tls: TlsConf = serde_yaml::from_str("min_version: 1.3\nmax_version: 1.3").unwrap();
+        assert_eq!(tls.versions().unwrap().len(), 1);
+
+        let tls: TlsConf = serde_yaml::from_str(
+            "cipher_suites: [TLS13_AES_256_GCM_SHA384, tls_ecdhe_ecdsa_with_chacha20_poly1305_sha256]\n\
+             kx_groups: [X25519]",
+        )
+        .unwrap();
+        assert_eq!(tls.suites().unwrap().len(), 2);
+        assert_eq!(tls.kx_groups().unwrap().len(), 1);
+
+        for bad in [
+            "min_version: \"1.1\"",
+            "min_version: 1",
+            "min_version: \"1.3\"\nmax_version: \"1.2\"",
+            "cipher_suites: [TLS_RSA_WITH_RC4_128_SHA]",
+            "kx_groups: [ffdhe2048]",
+        ] {
+            let tls: TlsConf = serde_yaml::from_str(bad).unwrap();
+            assert!(tls.validate().is_err(), "{}", bad);
+        }
+    }
+
+    struct AnyServerCert;
+
+    impl rustls::client::ServerCertVerifier for AnyServerCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &rustls::ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        }
+    }
+
+    // The certificate a client sending `sni` is given.
+    async fn served_cert(acceptor: &Acceptor, sni: Option<&str>) -> Certificate {
+        let mut config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyServerCert))
+            .with_no_client_auth();
+        config.enable_sni = sni.is_some();
+        let name = rustls::ServerName::try_from(sni.unwrap_or("unused.test")).unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = acceptor.clone();
+        let server = tokio::spawn(async move { acceptor.accept(server).await });
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name, client)
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+
+        stream.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn sni() {
+        let dir = std::env::temp_dir().join(format!("laika-sni-{}", std::process::id()));
+        let conf = conf::Conf::test_hosts(&dir, "hostnames: [\"a.test\"]");
+        let acceptor = conf.tls_acceptor().unwrap();
+        let cert = |name: &str| conf.host(name).unwrap().tls_cert()[0].clone();
+
+        assert_eq!(served_cert(&acceptor, Some("b.test")).await, cert("b.test"));
+        assert_eq!(
+            served_cert(&acceptor, Some("WWW.b.test")).await,
+            cert("b.test")
+        );
+        assert_eq!(served_cert(&acceptor, Some("c.test")).await, cert("a.test"));
+        assert_eq!(served_cert(&acceptor, None).await, cert("a.test"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Notes whether the server asked for a client certificate.
+    struct Asked(std::sync::atomic::AtomicBool);
+
+    impl rustls::client::ResolvesClientCert for Asked {
+        fn resolve(
+            &self,
+            _acceptable_issuers: &[&[u8]],
+            _sigschemes: &[rustls::SignatureScheme],
+        ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+            self.0.store(true, std::sync::atomic::Ordering::SeqCst);
+            None
+        }
+
+        fn has_certs(&self) -> bool {
+            true
+        }
+    }
+
+    async fn asks_for_cert(acceptor: &Acceptor, sni: &str) -> bool {
+        let asked = Arc::new(Asked(std::sync::atomic::AtomicBool::new(false)));
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyServerCert))
+            .with_client_cert_resolver(asked.clone());
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let acceptor = acceptor.clone();
+        let server = tokio::spawn(async move { acceptor.accept(server).await });
+        let _stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(rustls::ServerName::try_from(sni).unwrap(), client)
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+
+        asked.0.load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn client_auth() {
+        let dir = std::env::temp_dir().join(format!("laika-client-auth-{}", std::process::id()));
+        // the first line lands in b.test, which opts out of the routes
+        let extra = "    client_certificates: []
+client_certificates:
+  - path: \"/private\"";
+        let conf = conf::Conf::test_hosts(&dir, extra);
+        let acceptor = conf.tls_acceptor().unwrap();
+
+        assert!(asks_for_cert(&acceptor, "a.test").await);
+        assert!(!asks_for_cert(&acceptor, "b.test").await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
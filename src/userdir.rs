@@ -0,0 +1,194 @@
+/* Copyright (C) 2023  Ben Morrison <ben@gbmor.org>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https: *www.gnu.org/licenses/>.
+ */
+
+use std::ffi::{CStr, CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::err::Supernova;
+use crate::response;
+
+/// Settings for serving `/~user/` from each user's own directory.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UserDirConf {
+    enabled: bool,
+    // {user} is replaced with the user name and {home} with their home
+    // directory from the passwd database
+    path: String,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    // whether CGI scripts are run from user directories. they run as the
+    // laika user, so anyone with a user directory could read its keys
+    cgi: bool,
+}
+
+impl Default for UserDirConf {
+    fn default() -> Self {
+        UserDirConf {
+            enabled: false,
+            path: String::from("{home}/public_gemini"),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            cgi: false,
+        }
+    }
+}
+
+impl UserDirConf {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn cgi(&self) -> bool {
+        self.cgi
+    }
+
+    /// Whether `user` may have their directory served. An empty allow list
+    /// lets everyone in who isn't denied.
+    pub fn permits(&self, user: &str) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|u| u == user);
+        allowed && !self.deny.iter().any(|u| u == user)
+    }
+
+    /// Finds the content root for `user`. Users that aren't permitted, don't
+    /// exist, or don't have the directory are all reported as not found.
+    pub async fn root(&self, user: &str) -> Result<PathBuf, Supernova> {
+        let not_found = |why: &str| {
+            let msg = format!("user directory for {}: {}", user, why);
+            Supernova::boom(&msg).with_code(response::Code::NotFound)
+        };
+
+        if !self.permits(user) {
+            return Err(not_found("user is not permitted"));
+        }
+
+        let name = user.to_string();
+        let home = match tokio::task::spawn_blocking(move || home_directory(&name)).await {
+            Ok(Some(v)) => v,
+            Ok(None) => return Err(not_found("no such user")),
+            Err(e) => return Err(not_found(&e.to_string())),
+        };
+
+        let root = PathBuf::from(
+            self.path
+                .replace("{home}", &home.to_string_lossy())
+                .replace("{user}", user),
+        );
+
+        match fs::metadata(&root).await {
+            Ok(m) if m.is_dir() => Ok(root),
+            _ => Err(not_found(&format!("{} is not a directory", root.display()))),
+        }
+    }
+}
+
+/// Splits a decoded request path like `/~alice/notes.gmi` into the user
+/// name and the path below their directory.
+pub fn split_user_path(req_path: &str) -> Option<(&str, &str)> {
+    let rest = req_path.strip_prefix("/~")?;
+    let (user, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+
+    if user.is_empty() {
+        None
+    } else {
+        Some((user, path))
+    }
+}
+
+// Looks the user up in the system passwd database, which may mean NSS
+// talking to the network, so this blocks.
+fn home_directory(user: &str) -> Option<PathBuf> {
+    let name = CString::new(user).ok()?;
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    // SAFETY: passwd is a plain C struct; all-zero is a valid value and
+    // getpwnam_r overwrites it on success.
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = ptr::null_mut();
+
+    loop {
+        // SAFETY: every pointer refers to memory we own for the whole call,
+        // and buf.len() is the real size of buf.
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        break;
+    }
+
+    if result.is_null() || pwd.pw_dir.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success pw_dir points at a NUL-terminated string in buf.
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Some(PathBuf::from(OsStr::from_bytes(home.to_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_paths() {
+        assert_eq!(split_user_path("/~alice"), Some(("alice", "/")));
+        assert_eq!(split_user_path("/~alice/"), Some(("alice", "/")));
+        assert_eq!(
+            split_user_path("/~alice/a/b.gmi"),
+            Some(("alice", "/a/b.gmi"))
+        );
+        assert_eq!(split_user_path("/~"), None);
+        assert_eq!(split_user_path("/~/x"), None);
+        assert_eq!(split_user_path("/alice"), None);
+        assert_eq!(split_user_path("/a/~alice"), None);
+    }
+
+    #[test]
+    fn allow_and_deny() {
+        let mut conf = UserDirConf::default();
+        assert!(conf.permits("alice"));
+
+        conf.deny.push(String::from("root"));
+        assert!(conf.permits("alice"));
+        assert!(!conf.permits("root"));
+
+        conf.allow.push(String::from("bob"));
+        assert!(!conf.permits("alice"));
+        assert!(conf.permits("bob"));
+    }
+
+    #[test]
+    fn passwd_lookup() {
+        assert_eq!(home_directory("root"), Some(PathBuf::from("/root")));
+        assert_eq!(home_directory("no-such-user-laika"), None);
+    }
+}